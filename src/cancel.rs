@@ -0,0 +1,75 @@
+//! Cooperative cancellation and progress reporting for long searches.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+/// A cheap, cloneable flag that asks a running search to stop.
+///
+/// Clones share the same flag, so one can be handed to a background search
+/// while another stays with the UI that decides to abort it.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How far a search got, reported through the progress callback.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub scanned_lines: usize,
+    pub matched_lines: usize,
+    pub bytes_read: u64,
+    /// Size of the input when known up front, 0 otherwise
+    pub total_bytes: u64,
+}
+
+/// Returned as the error of a search that stopped because its token was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "search cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+static INTERRUPT: OnceLock<CancellationToken> = OnceLock::new();
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    if let Some(token) = INTERRUPT.get() {
+        token.cancel();
+    }
+    // A second Ctrl+C kills the process as usual
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+/// Cancels `token` on the first SIGINT instead of killing the process.
+///
+/// Only the first token registered is wired up; later calls return `false`.
+pub fn cancel_on_interrupt(token: &CancellationToken) -> bool {
+    if INTERRUPT.set(token.clone()).is_err() {
+        return false;
+    }
+    let handler: extern "C" fn(libc::c_int) = on_interrupt;
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+    true
+}
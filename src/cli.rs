@@ -0,0 +1,172 @@
+//! Flag definitions shared by `Config::build`, `--help` and the shell completions.
+
+use crate::codec::CODECS;
+use crate::format::FORMATS;
+
+/// Boolean flags, as `--name`, with their help text
+pub const FLAGS: &[(&str, &str)] = &[
+    ("ignore-case", "match regardless of case (env: IGNORE_CASE)"),
+    ("no-color", "don't highlight matches (env: NO_COLOR)"),
+    ("line-number", "show line numbers (env: LINE_NUMBER)"),
+    ("stats", "print match statistics (env: STATS)"),
+    ("no-alt-screen", "page in the main screen buffer (env: NO_ALT_SCREEN)"),
+    ("always-page", "page even when the results fit on one screen (env: ALWAYS_PAGE)"),
+    ("strip-ansi", "drop the input's own colors from the output (env: STRIP_ANSI)"),
+    ("json", "shorthand for --format-name=json (env: JSON)"),
+    ("csv", "shorthand for --format-name=csv (env: CSV)"),
+    ("hash", "add a stable line fingerprint to json, csv and sarif output"),
+    ("regex", "treat the query as a regular expression"),
+    ("invert-match", "keep the lines that don't match"),
+    ("then", "start a stage searching the results: --then [--invert-match] [--ignore-case] [--regex] QUERY"),
+    ("quiet", "print nothing, exit with 1 when nothing matched and 2 on errors"),
+    ("query-from-stdin", "read the query from the first line of stdin, so only FILE is given"),
+    ("truncate", "cut long lines to the terminal width around the match (env: TRUNCATE)"),
+    ("show-invisibles", "show tabs as →, trailing spaces as · and carriage returns as ␍"),
+    ("suggest", "when nothing matches, suggest similar words from the file"),
+    ("rank", "show the most relevant lines first: whole words, then closer words, then more matches"),
+    ("check", "same as 'minigrep check': describe the search without running it"),
+    ("help", "print this help"),
+];
+
+/// Environment variables that turn on a flag of `FLAGS` just by being set
+pub const ENV_FLAGS: &[&str] =
+    &["IGNORE_CASE", "NO_COLOR", "LINE_NUMBER", "STATS", "NO_ALT_SCREEN", "ALWAYS_PAGE", "STRIP_ANSI", "TRUNCATE", "JSON", "CSV"];
+
+/// Flags taking a value, as `--name=VALUE`, with a value placeholder and help text
+pub const VALUE_FLAGS: &[(&str, &str, &str)] = &[
+    ("group-by", "N", "group results by the value of capture group N"),
+    ("top", "N", "print only the N most frequent matched values"),
+    ("map", "EXPR", "reshape matched lines: field:N, field:N:SEP or extract:REGEX"),
+    ("where", "EXPR", "keep lines whose number passes a comparison: 'key > 5' or '$N > 5' for a capture group"),
+    ("max-count", "N", "stop after N matching lines"),
+    ("max-line-length", "N", "only search the first N bytes of each line (also 512K, 1M...)"),
+    ("max-memory", "SIZE", "cap memory kept for results and lines read, e.g. 64M; later matches are dropped"),
+    ("root", "DIR", "find FILE under DIR, or under the nearest .git or Cargo.toml ancestor with 'auto'"),
+    ("theme", "THEME", "secondary colors: auto, light or dark"),
+    ("render", "MODE", "ansi, or ansi-debug to mark matches as <<match>> without escape codes"),
+    ("export", "PATH", "save lines tagged with 1-3 in the pager to PATH.N files, or a PATH.json report"),
+    ("format-name", "NAME", "print results in a non-interactive format (see below)"),
+    ("completions", "SHELL", "print a completion script for bash or fish"),
+    ("serve", "SOCKET", "answer searches from --client over a Unix socket, keeping files cached"),
+    ("client", "SOCKET", "forward this search to a running --serve daemon"),
+];
+
+pub fn is_flag(name: &str) -> bool {
+    FLAGS.iter().any(|(flag, _)| *flag == name)
+}
+
+pub fn is_value_flag(name: &str) -> bool {
+    VALUE_FLAGS.iter().any(|(flag, _, _)| *flag == name)
+}
+
+/// Every flag name, for suggestions
+pub fn flag_names() -> impl Iterator<Item = &'static str> {
+    FLAGS.iter().map(|(flag, _)| *flag).chain(VALUE_FLAGS.iter().map(|(flag, _, _)| *flag))
+}
+
+pub fn help() -> String {
+    let mut help = String::from(
+        "Usage: minigrep QUERY FILE [FLAGS]\n       minigrep FILE [FLAGS]  (asks for the query)\n       minigrep --query-from-stdin FILE [FLAGS]\n       minigrep check QUERY FILE [FLAGS]  (validates without searching)\n       minigrep --serve [SOCKET]\n       minigrep --client[=SOCKET] QUERY FILE [FLAGS]\n\nArguments after '--' are never flags, as in 'minigrep --stats -- --help notes.txt',\nor 'minigrep -- check notes.txt' to search for \"check\".\n\nFlags:\n",
+    );
+
+    let width = FLAGS
+        .iter()
+        .map(|(flag, _)| flag.len())
+        .chain(VALUE_FLAGS.iter().map(|(flag, value, _)| flag.len() + value.len() + 1))
+        .max()
+        .unwrap_or(0);
+
+    for (flag, description) in FLAGS {
+        help.push_str(&format!("  --{flag:<width$}  {description}\n"));
+    }
+    for (flag, value, description) in VALUE_FLAGS {
+        let flag = format!("{flag}={value}");
+        help.push_str(&format!("  --{flag:<width$}  {description}\n"));
+    }
+
+    help.push_str("\nFormats:\n");
+    let width = FORMATS.iter().map(|format| format.name.len()).max().unwrap_or(0);
+    for format in FORMATS {
+        help.push_str(&format!("  {:<width$}  {}\n", format.name, format.description));
+    }
+
+    if !CODECS.is_empty() {
+        let names: Vec<&str> = CODECS.iter().map(|codec| codec.name).collect();
+        help.push_str(&format!(
+            "\nCompressed files are searched as they are: {}\nThis runs the program of the same name, files it can't decode are searched as plain text.\n",
+            names.join(", ")
+        ));
+    }
+    help
+}
+
+pub fn completions(shell: &str) -> Result<String, String> {
+    let formats = FORMATS.iter().map(|format| format.name).collect::<Vec<_>>().join(" ");
+
+    match shell {
+        "bash" => {
+            let flags = FLAGS
+                .iter()
+                .map(|(flag, _)| format!("--{flag}"))
+                .chain(VALUE_FLAGS.iter().map(|(flag, _, _)| format!("--{flag}=")))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            Ok(format!(
+                r#"_minigrep() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    case "$cur" in
+        --format-name=*) COMPREPLY=($(compgen -P "--format-name=" -W "{formats}" -- "${{cur#--format-name=}}")) ;;
+        --theme=*) COMPREPLY=($(compgen -P "--theme=" -W "auto light dark" -- "${{cur#--theme=}}")) ;;
+        --render=*) COMPREPLY=($(compgen -P "--render=" -W "ansi ansi-debug" -- "${{cur#--render=}}")) ;;
+        --*) COMPREPLY=($(compgen -W "{flags}" -- "$cur")); compopt -o nospace ;;
+        *) COMPREPLY=($(compgen -f -- "$cur")) ;;
+    esac
+}}
+complete -F _minigrep minigrep
+"#
+            ))
+        }
+        "fish" => {
+            let mut script = String::new();
+            // Single quotes in fish only need their own quote escaped
+            let quote = |s: &str| s.replace('\'', "\\'");
+            for (flag, description) in FLAGS {
+                script.push_str(&format!("complete -c minigrep -l {flag} -d '{}'\n", quote(description)));
+            }
+            for (flag, _, description) in VALUE_FLAGS {
+                let values = match *flag {
+                    "format-name" => format!(" -xa '{formats}'"),
+                    "theme" => " -xa 'auto light dark'".to_string(),
+                    "render" => " -xa 'ansi ansi-debug'".to_string(),
+                    "root" => " -xa 'auto (__fish_complete_directories)'".to_string(),
+                    _ => " -x".to_string(),
+                };
+                script.push_str(&format!("complete -c minigrep -l {flag}{values} -d '{}'\n", quote(description)));
+            }
+            Ok(script)
+        }
+        _ => Err(format!("Unsupported shell '{shell}'. Expected 'bash' or 'fish'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_show_up_in_help_and_completions() {
+        let help = help();
+        let bash = completions("bash").unwrap();
+        let fish = completions("fish").unwrap();
+
+        for format in FORMATS {
+            assert!(help.contains(format.name));
+            assert!(bash.contains(format.name));
+            assert!(fish.contains(format.name));
+        }
+        assert!(bash.contains("--max-count="));
+        assert!(fish.contains("-d 'don\\'t highlight matches"));
+        assert!(completions("tcsh").is_err());
+    }
+}
@@ -0,0 +1,234 @@
+//! Transparent decompression of input files.
+//!
+//! Each codec in `CODECS` is recognised by its magic bytes, or failing that by
+//! extension, and sits behind a cargo feature of the same name. Adding a format
+//! means implementing `Decoder` and registering it.
+//!
+//! The built-in codecs run the system's `gzip`, `bzip2`, `xz` and `zstd`
+//! programs, which have to be on the `PATH`. When the program is missing, or
+//! can't decode the file at all (a text file that happens to start with `BZh`,
+//! or a misnamed `.gz`), the file is read as it is.
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+/// How much of a decompressor's stderr is kept for the error message
+const MAX_STDERR_BYTES: u64 = 4096;
+
+pub trait Decoder: Sync {
+    /// Opens `path` and returns a reader over its decompressed contents.
+    fn decode(&self, path: &Path) -> io::Result<Box<dyn Read>>;
+}
+
+pub struct Codec {
+    pub name: &'static str,
+    /// Leading bytes every file in this format starts with
+    pub magic: &'static [u8],
+    pub extensions: &'static [&'static str],
+    pub decoder: &'static dyn Decoder,
+}
+
+pub static CODECS: &[Codec] = &[
+    #[cfg(feature = "gzip")]
+    Codec { name: "gzip", magic: &[0x1f, 0x8b], extensions: &["gz", "tgz"], decoder: &External("gzip") },
+    #[cfg(feature = "bzip2")]
+    Codec { name: "bzip2", magic: b"BZh", extensions: &["bz2", "tbz2"], decoder: &External("bzip2") },
+    #[cfg(feature = "xz")]
+    Codec { name: "xz", magic: &[0xfd, b'7', b'z', b'X', b'Z', 0x00], extensions: &["xz", "txz"], decoder: &External("xz") },
+    #[cfg(feature = "zstd")]
+    Codec { name: "zstd", magic: &[0x28, 0xb5, 0x2f, 0xfd], extensions: &["zst", "tzst"], decoder: &External("zstd") },
+];
+
+/// The codec for `path`, judged by its first bytes and then by its extension.
+pub fn detect(path: &Path) -> io::Result<Option<&'static Codec>> {
+    let mut head = [0; 8];
+    let mut len = 0;
+    let mut file = fs::File::open(path)?;
+    while len < head.len() {
+        match file.read(&mut head[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+
+    let by_magic = CODECS.iter().find(|codec| head[..len].starts_with(codec.magic));
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let by_extension = || CODECS.iter().find(|codec| extension.is_some_and(|extension| codec.extensions.contains(&extension)));
+    Ok(by_magic.or_else(by_extension))
+}
+
+/// Opens `path` for reading, decompressing it when a codec recognises it and
+/// can decode it. Also returns the codec used.
+pub fn open(path: &Path) -> io::Result<(Box<dyn Read>, Option<&'static Codec>)> {
+    if let Some(codec) = detect(path)?
+        && let Ok(reader) = codec.decoder.decode(path)
+    {
+        return Ok((reader, Some(codec)));
+    }
+    Ok((Box::new(fs::File::open(path)?), None))
+}
+
+/// Reads all of `path`, decompressed.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    open(path)?.0.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// Decompresses by piping the file through `PROGRAM -dc`, which gzip, bzip2,
+/// xz and zstd all understand.
+///
+/// Fails when the program can't be run, or exits with an error before giving
+/// any output, so that `open` can fall back to the file as it is.
+pub struct External(pub &'static str);
+
+impl Decoder for External {
+    fn decode(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        let mut child = Command::new(self.0)
+            .arg("-dc")
+            .arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| io::Error::new(err.kind(), format!("Can't run '{}' to decompress {}: {err}", self.0, path.display())))?;
+
+        let stdout = child.stdout.take().unwrap();
+        let mut stderr = child.stderr.take().unwrap();
+        // Drained alongside stdout, or a chatty program blocks on a full pipe
+        // while we wait for its output
+        let stderr = thread::spawn(move || {
+            let mut message = Vec::new();
+            let _ = (&mut stderr).take(MAX_STDERR_BYTES).read_to_end(&mut message);
+            let _ = io::copy(&mut stderr, &mut io::sink());
+            String::from_utf8_lossy(&message).into_owned()
+        });
+        let mut reader = ChildReader { program: self.0, child, stdout, stderr: Some(stderr) };
+
+        // A file it can't decode fails here, before the first output
+        let mut first = vec![0; 8192];
+        let read = reader.read(&mut first)?;
+        first.truncate(read);
+        Ok(Box::new(io::Cursor::new(first).chain(reader)))
+    }
+}
+
+/// Reads a decompressor's output, turning a failed exit into an error at the end.
+struct ChildReader {
+    program: &'static str,
+    child: Child,
+    stdout: ChildStdout,
+    stderr: Option<JoinHandle<String>>,
+}
+
+impl Read for ChildReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stdout.read(buf)?;
+        if read == 0 && !buf.is_empty() && let Some(stderr) = self.stderr.take() {
+            let message = stderr.join().unwrap_or_default();
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("{} failed ({status}): {}", self.program, message.trim())));
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl Drop for ChildReader {
+    fn drop(&mut self) {
+        // Searches can stop early, don't leave the decompressor behind
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+
+    /// A temp file path of the test's own, so parallel runs don't collide
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("minigrep_codec_{}_{name}", std::process::id()))
+    }
+
+    #[test]
+    fn plain_files_pass_through() {
+        let path = temp_path("plain.txt");
+        fs::write(&path, "not compressed").unwrap();
+
+        assert!(detect(&path).unwrap().is_none());
+        assert_eq!(read(&path).unwrap(), b"not compressed");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn missing_programs_fail_to_decode() {
+        let path = temp_path("missing_program.txt");
+        fs::write(&path, "plain text").unwrap();
+
+        assert!(External("minigrep-no-such-decompressor").decode(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn chatty_decompressors_dont_block() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Fills the stderr pipe several times over before any output
+        let program = temp_path("chatty.sh");
+        fs::write(&program, "#!/bin/sh\nhead -c 1000000 /dev/zero >&2\necho decoded\nexit 3\n").unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        let path = temp_path("chatty.txt");
+        fs::write(&path, "input").unwrap();
+
+        let program: &'static str = Box::leak(program.to_string_lossy().into_owned().into_boxed_str());
+        let mut reader = External(program).decode(&path).unwrap();
+        let mut contents = String::new();
+        let err = reader.read_to_string(&mut contents).unwrap_err();
+        assert_eq!(contents, "decoded\n");
+        assert!(err.to_string().contains("exit status: 3"));
+        fs::remove_file(program).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_by_magic_bytes() {
+        let path = temp_path("gzip.log");
+        let Ok(output) = Command::new("gzip").arg("-c").stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().and_then(|mut gzip| {
+            use std::io::Write;
+            gzip.stdin.take().unwrap().write_all(b"one\ntwo\n")?;
+            gzip.wait_with_output()
+        }) else {
+            // No gzip to make the fixture with
+            return;
+        };
+        fs::write(&path, output.stdout).unwrap();
+
+        assert_eq!(detect(&path).unwrap().map(|codec| codec.name), Some("gzip"));
+        assert_eq!(read(&path).unwrap(), b"one\ntwo\n");
+
+        // Recognised by extension too, and read as it is when it isn't really gzip
+        let fake = temp_path("fake.gz");
+        fs::write(&fake, "plain text").unwrap();
+        assert_eq!(read(&fake).unwrap(), b"plain text");
+        fs::remove_file(path).unwrap();
+        fs::remove_file(fake).unwrap();
+    }
+
+    #[cfg(feature = "bzip2")]
+    #[test]
+    fn text_that_looks_like_bzip2() {
+        let path = temp_path("looks_like_bzip2.txt");
+        fs::write(&path, "BZh is how bzip2 files start").unwrap();
+
+        assert_eq!(read(&path).unwrap(), b"BZh is how bzip2 files start");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,439 @@
+//! `--serve`: a long-running process that answers searches over a Unix socket,
+//! keeping file contents cached between requests, and `--client`, which
+//! forwards a normal invocation to it.
+//!
+//! Each connection carries one request and one response, each a single line of JSON:
+//!
+//! ```text
+//! {"cwd":"/home/me/project","args":["QUERY","FILE","--stats"],"env":["IGNORE_CASE"],"color":true}
+//! {"ok":true,"matched":true,"status":0,"stdout":"...","stderr":"..."}
+//! {"ok":false,"status":1,"error":"..."}
+//! ```
+//!
+//! `env` lists the client's `cli::ENV_FLAGS` that are set; the daemon's own
+//! environment is never used for a request. `check` works as it does locally,
+//! `--export` doesn't as there is no pager to tag lines in.
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::cli::ENV_FLAGS;
+use crate::codec;
+use crate::json::{self, field, Value};
+use crate::{
+    check, check_args, format, print_rows, render, scan_file, search_bytes, suggestion, CancellationToken, Config, Rendered,
+};
+
+/// Total size of the file contents the daemon keeps cached
+const MAX_CACHED_BYTES: u64 = 256 << 20;
+/// Requests answered at once; more are turned away until one finishes
+const MAX_CONNECTIONS: usize = 32;
+/// Longest a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REQUEST_BYTES: u64 = 1 << 20;
+
+/// Where `--serve` and `--client` meet when no socket is given: the user's
+/// runtime directory, or a directory of their own under the temp dir.
+pub fn default_socket() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("minigrep.sock"),
+        _ => env::temp_dir().join(format!("minigrep-{}", unsafe { libc::getuid() })).join("minigrep.sock"),
+    }
+}
+
+/// Refuses a socket directory someone else could have planted or can reach
+/// into, as the temp dir fallback of `default_socket` is open to everyone.
+fn check_private(dir: &Path) -> Result<(), Box<dyn Error>> {
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != unsafe { libc::getuid() } || metadata.mode() & 0o077 != 0 {
+        return Err(format!("{} must be a directory of your own that only you can access (mode 700)", dir.display()).into());
+    }
+    Ok(())
+}
+
+struct CachedFile {
+    modified: SystemTime,
+    len: u64,
+    contents: Arc<Vec<u8>>,
+    /// When the file was last searched, as a value of `Files::clock`
+    used: u64,
+}
+
+#[derive(Default)]
+struct Files {
+    entries: HashMap<PathBuf, CachedFile>,
+    bytes: u64,
+    clock: u64,
+}
+
+/// File contents kept between requests, reloaded when a file's size or
+/// modification time changes. The least recently searched files are dropped
+/// once the cache outgrows its budget.
+pub struct Cache {
+    files: Mutex<Files>,
+    budget: u64,
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        Cache::with_budget(MAX_CACHED_BYTES)
+    }
+}
+
+impl Cache {
+    pub fn with_budget(budget: u64) -> Cache {
+        Cache { files: Mutex::default(), budget }
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Arc<Vec<u8>>> {
+        let metadata = fs::metadata(path)?;
+        let (modified, len) = (metadata.modified()?, metadata.len());
+
+        {
+            let mut files = self.files.lock().unwrap();
+            files.clock += 1;
+            let clock = files.clock;
+            if let Some(cached) = files.entries.get_mut(path)
+                && cached.modified == modified
+                && cached.len == len
+            {
+                cached.used = clock;
+                return Ok(Arc::clone(&cached.contents));
+            }
+        }
+
+        // Read without holding the lock so slow files don't stall other requests
+        let contents = Arc::new(codec::read(path)?);
+        let size = contents.len() as u64;
+        let mut files = self.files.lock().unwrap();
+        if let Some(stale) = files.entries.remove(path) {
+            files.bytes -= stale.contents.len() as u64;
+        }
+        if size > self.budget {
+            return Ok(contents);
+        }
+        while files.bytes + size > self.budget {
+            let Some(oldest) = files.entries.iter().min_by_key(|(_, cached)| cached.used).map(|(path, _)| path.clone()) else {
+                break;
+            };
+            let evicted = files.entries.remove(&oldest).unwrap();
+            files.bytes -= evicted.contents.len() as u64;
+        }
+        let used = files.clock;
+        files.bytes += size;
+        files.entries.insert(path.to_path_buf(), CachedFile { modified, len, contents: Arc::clone(&contents), used });
+        Ok(contents)
+    }
+}
+
+/// Answers one request line.
+pub fn answer(request: &str, cache: &Cache) -> Result<Value, Box<dyn Error>> {
+    let request = json::parse(request.trim())?;
+    let args = request
+        .get("args")
+        .and_then(Value::as_array)
+        .ok_or("Request needs an 'args' array")?;
+
+    let mut argv = vec!["minigrep".to_string()];
+    for arg in args {
+        argv.push(arg.as_str().ok_or("Request 'args' must all be strings")?.to_string());
+    }
+    let mut env = Vec::new();
+    for name in request.get("env").and_then(Value::as_array).unwrap_or_default() {
+        env.push(name.as_str().ok_or("Request 'env' must all be strings")?.to_string());
+    }
+
+    let checking = check_args(&argv);
+    let argv = checking.as_deref().unwrap_or(&argv);
+    // The daemon's own stdin belongs to whoever started it
+    let mut config =
+        Config::build_with_env(argv, env, |_| Err("Pass the query in 'args', the daemon can't ask for it".to_string()))?;
+    if config.export.is_some() {
+        return Err("'--export' tags lines in the pager, which a '--client' search doesn't have".into());
+    }
+    if request.get("color").and_then(Value::as_bool) != Some(true) {
+        config.no_color = true;
+    }
+    // Nobody can page over a socket
+    if config.format.is_none() {
+        config.format = format::find("plain");
+    }
+
+    let path = match request.get("cwd").and_then(Value::as_str) {
+        Some(cwd) => config.path_in(Path::new(cwd))?,
+        None => config.path()?,
+    };
+    if checking.is_some() {
+        config.file_path = path.to_string_lossy().into_owned();
+        config.root = None;
+        let report = check(&config)?;
+        return Ok(Value::Object(vec![
+            field("ok", Value::Bool(true)),
+            field("matched", Value::Bool(false)),
+            field("status", Value::Number(0.0)),
+            field("stdout", Value::String(report)),
+            field("stderr", Value::String(String::new())),
+        ]));
+    }
+    let mut options = config.search_options();
+    if config.quiet {
+        options.max_count = Some(1);
+    }
+    // Holding the whole file would break `--max-memory`, so stream it past the cache
+    let contents = match config.max_memory {
+        Some(budget) if codec::detect(&path)?.is_some() || fs::metadata(&path)?.len() > budget as u64 => None,
+        _ => Some(cache.read(&path)?),
+    };
+    let results = match &contents {
+        Some(contents) => search_bytes(contents, &config.matcher(), &options),
+        None => scan_file(&path, &config.matcher(), &options, &CancellationToken::new(), &mut |_| {})?.0,
+    };
+
+    let (mut out, mut diag) = (Vec::new(), Vec::new());
+    let matched = if config.quiet {
+        !results.matches.is_empty()
+    } else {
+        match render(results, &config, &mut out, &mut diag)? {
+            Rendered::Written(matched) => matched,
+            Rendered::Paged(rows, indexes, _) => {
+                print_rows(&mut out, &rows, &indexes, &config)?;
+                true
+            }
+        }
+    };
+
+    if !matched && config.suggest && !config.quiet {
+        let hint = match &contents {
+            Some(contents) => suggestion(&contents[..], &config)?,
+            None => suggestion(BufReader::new(codec::open(&path)?.0), &config)?,
+        };
+        if let Some(hint) = hint {
+            writeln!(diag, "{hint}")?;
+        }
+    }
+
+    let status = if config.quiet && !matched { 1.0 } else { 0.0 };
+    Ok(Value::Object(vec![
+        field("ok", Value::Bool(true)),
+        field("matched", Value::Bool(matched)),
+        field("status", Value::Number(status)),
+        field("stdout", Value::String(String::from_utf8_lossy(&out).into_owned())),
+        field("stderr", Value::String(String::from_utf8_lossy(&diag).into_owned())),
+    ]))
+}
+
+fn failure(message: &str) -> Value {
+    Value::Object(vec![
+        field("ok", Value::Bool(false)),
+        field("status", Value::Number(1.0)),
+        field("error", Value::String(message.to_string())),
+    ])
+}
+
+fn handle(stream: UnixStream, cache: &Cache) -> io::Result<()> {
+    // A client that never finishes its request mustn't hold on to a slot
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream).take(MAX_REQUEST_BYTES).read_line(&mut line)?;
+
+    let response = answer(&line, cache).unwrap_or_else(|err| failure(&err.to_string()));
+    writeln!(&stream, "{response}")
+}
+
+/// Serves requests on `socket` until the process is killed.
+pub fn serve(socket: &Path) -> Result<(), Box<dyn Error>> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(format!("A daemon is already listening on {}", socket.display()).into());
+        }
+        // Left behind by a daemon that didn't shut down cleanly
+        fs::remove_file(socket)?;
+    }
+    if let Some(dir) = socket.parent().filter(|dir| !dir.as_os_str().is_empty() && !dir.exists()) {
+        // Only this user may reach the default socket
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    if socket == default_socket() {
+        check_private(socket.parent().unwrap_or(socket))?;
+    }
+
+    let listener = UnixListener::bind(socket)?;
+    // Whether to color is decided per request, from the client's terminal
+    colored::control::set_override(true);
+    eprintln!("Listening on {}", socket.display());
+
+    let cache = Arc::new(Cache::default());
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = stream?;
+        if active.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+            let _ = writeln!(&stream, "{}", failure("The daemon is busy, try again shortly"));
+            continue;
+        }
+        active.fetch_add(1, Ordering::SeqCst);
+        let (cache, active) = (Arc::clone(&cache), Arc::clone(&active));
+        thread::spawn(move || {
+            if let Err(err) = handle(stream, &cache) {
+                eprintln!("Request failed: {err}");
+            }
+            active.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+/// Sends one request over `stream` and reads the response.
+pub fn exchange(mut stream: UnixStream, request: &Value) -> Result<Value, Box<dyn Error>> {
+    writeln!(stream, "{request}")?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    Ok(json::parse(line.trim())?)
+}
+
+/// Forwards `args` (everything after the program name) to the daemon on
+/// `socket`, prints its output and returns the exit status to use.
+pub fn client(socket: &Path, args: &[String]) -> Result<i32, Box<dyn Error>> {
+    if socket == default_socket() && socket.exists() {
+        check_private(socket.parent().unwrap_or(socket))?;
+    }
+    let stream = UnixStream::connect(socket)
+        .map_err(|err| format!("Can't reach a daemon on {} ({err}). Start one with 'minigrep --serve'", socket.display()))?;
+
+    let request = Value::Object(vec![
+        field("cwd", Value::String(env::current_dir()?.to_string_lossy().into_owned())),
+        field("args", Value::Array(args.iter().cloned().map(Value::String).collect())),
+        field(
+            "env",
+            Value::Array(ENV_FLAGS.iter().filter(|name| env::var_os(name).is_some()).map(|name| Value::String(name.to_string())).collect()),
+        ),
+        field("color", Value::Bool(termion::is_tty(&io::stdout()))),
+    ]);
+    let response = exchange(stream, &request)?;
+
+    if response.get("ok").and_then(Value::as_bool) != Some(true) {
+        let error = response.get("error").and_then(Value::as_str).unwrap_or("unknown error");
+        return Err(error.into());
+    }
+
+    print!("{}", response.get("stdout").and_then(Value::as_str).unwrap_or_default());
+    eprint!("{}", response.get("stderr").and_then(Value::as_str).unwrap_or_default());
+    Ok(match response.get("status") {
+        Some(Value::Number(status)) => *status as i32,
+        _ => 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_over_a_socket_pair() {
+        let dir = env::temp_dir().join("minigrep_daemon_test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("poem.txt"), "Rust:\nsafe, fast, productive.\nPick three.").unwrap();
+
+        let (client_end, server_end) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || handle(server_end, &Cache::default()));
+
+        let request = Value::Object(vec![
+            field("cwd", Value::String(dir.to_string_lossy().into_owned())),
+            field("args", Value::Array(vec![Value::String("fast".into()), Value::String("poem.txt".into())])),
+        ]);
+        let response = exchange(client_end, &request).unwrap();
+        server.join().unwrap().unwrap();
+
+        assert_eq!(response.get("matched").and_then(Value::as_bool), Some(true));
+        assert_eq!(response.get("stdout").and_then(Value::as_str), Some("safe, fast, productive.\n"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reports_errors_and_reuses_cache() {
+        let cache = Cache::default();
+        assert!(answer(r#"{"args":["q"]}"#, &cache).is_err());
+        assert!(answer("not json", &cache).is_err());
+
+        let path = env::temp_dir().join("minigrep_daemon_cache.txt");
+        fs::write(&path, "one\ntwo\n").unwrap();
+        let request = format!(r#"{{"args":["o","{}","--quiet"]}}"#, path.display());
+
+        let first = answer(&request, &cache).unwrap();
+        assert_eq!(first.get("status"), Some(&Value::Number(0.0)));
+        assert_eq!(cache.files.lock().unwrap().entries.len(), 1);
+        answer(&request, &cache).unwrap();
+        assert_eq!(cache.files.lock().unwrap().entries.len(), 1);
+
+        // Over the memory budget the file is streamed instead of cached
+        fs::write(&path, format!("one\ntwo\n{}", "-\n".repeat(1024))).unwrap();
+        let cache = Cache::default();
+        let request = format!(r#"{{"args":["o","{}","--max-memory=1K"]}}"#, path.display());
+        let response = answer(&request, &cache).unwrap();
+        assert_eq!(response.get("stdout").and_then(Value::as_str), Some("one\ntwo\n"));
+        assert!(cache.files.lock().unwrap().entries.is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn requests_carry_their_own_environment() {
+        let path = env::temp_dir().join(format!("minigrep_daemon_env_{}.txt", std::process::id()));
+        fs::write(&path, "Rust\nrust\n").unwrap();
+        let cache = Cache::default();
+        let stdout = |request: String| answer(&request, &cache).unwrap().get("stdout").and_then(Value::as_str).map(str::to_string);
+
+        let search = format!(r#"{{"args":["rust","{}"],"env":["IGNORE_CASE"]}}"#, path.display());
+        assert_eq!(stdout(search).as_deref(), Some("Rust\nrust\n"));
+
+        let check = format!(r#"{{"args":["check","rust","{}"]}}"#, path.display());
+        assert!(stdout(check).unwrap().starts_with("Query: \"rust\" (literal)"));
+
+        let export = format!(r#"{{"args":["rust","{}","--export=tags"]}}"#, path.display());
+        assert!(answer(&export, &cache).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn socket_directory_must_be_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("minigrep_daemon_private_{}", std::process::id()));
+        fs::DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        assert!(check_private(&dir).is_ok());
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(check_private(&dir).is_err());
+        fs::remove_dir(dir).unwrap();
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let dir = env::temp_dir().join(format!("minigrep_daemon_evict_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(dir.join(name), "0123456789").unwrap();
+        }
+
+        let cache = Cache::with_budget(25);
+        cache.read(&dir.join("a")).unwrap();
+        cache.read(&dir.join("b")).unwrap();
+        cache.read(&dir.join("a")).unwrap();
+        cache.read(&dir.join("c")).unwrap();
+
+        let files = cache.files.lock().unwrap();
+        assert_eq!(files.bytes, 20);
+        assert!(files.entries.contains_key(&dir.join("a")));
+        assert!(!files.entries.contains_key(&dir.join("b")));
+        drop(files);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+use crate::pattern::Regex;
+
+/// Where `--where` finds the number to compare.
+#[derive(Debug, Clone)]
+pub enum Field {
+    /// The value after `key=` or `key:` in the line
+    Key(String),
+    /// Capture group N of the `--regex` query, written `$N`
+    Group(usize, Regex),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+/// A numeric comparison matched lines must pass (`--where='FIELD OP NUMBER'`).
+///
+/// - `latency_ms > 500` reads the number from `latency_ms=812` or `latency_ms: 812`
+/// - `$2 >= 400` reads it from the second capture group of the query
+///
+/// Lines without the field, or where it isn't a number, are dropped.
+#[derive(Debug, Clone)]
+pub struct Where {
+    pub field: Field,
+    pub op: Op,
+    pub value: f64,
+}
+
+impl Where {
+    /// Parses `expr`. `regex` is the query when it is a regular expression,
+    /// which `$N` fields read from.
+    pub fn parse(expr: &str, regex: Option<&Regex>) -> Result<Where, String> {
+        let usage = || format!("Invalid comparison '{expr}'. Use 'FIELD OP NUMBER' with OP one of < <= > >= == !=");
+
+        let at = expr.find(['<', '>', '=', '!']).ok_or_else(usage)?;
+        let (field, rest) = expr.split_at(at);
+        let (op, number) = [("<=", Op::Le), (">=", Op::Ge), ("==", Op::Eq), ("!=", Op::Ne), ("<", Op::Lt), (">", Op::Gt), ("=", Op::Eq)]
+            .into_iter()
+            .find_map(|(symbol, op)| rest.strip_prefix(symbol).map(|number| (op, number)))
+            .ok_or_else(usage)?;
+        let value = number.trim().parse().map_err(|_| usage())?;
+
+        let field = field.trim();
+        let field = match field.strip_prefix('$') {
+            Some(group) => {
+                let group: usize = group.parse().map_err(|_| usage())?;
+                let Some(regex) = regex else {
+                    return Err(format!("'--where' on '${group}' needs a capture group, so it only works with '--regex'"));
+                };
+                let available = regex.group_count();
+                if group == 0 || group > available {
+                    return Err(format!("'${group}' in '--where' is out of range, the pattern has {available} capture group(s)"));
+                }
+                Field::Group(group, regex.clone())
+            }
+            None if !field.is_empty() && field.chars().all(is_key_char) => Field::Key(field.to_string()),
+            None => return Err(usage()),
+        };
+
+        Ok(Where { field, op, value })
+    }
+
+    /// The field's number in `line`, if it has one.
+    pub fn value_in(&self, line: &str) -> Option<f64> {
+        match &self.field {
+            Field::Key(key) => line.match_indices(key.as_str()).find_map(|(at, _)| {
+                // Don't read `total_latency_ms=` for `latency_ms`
+                if line[..at].chars().next_back().is_some_and(is_key_char) {
+                    return None;
+                }
+                // Quoted keys as in JSON
+                let rest = line[at + key.len()..].trim_start_matches(['"', '\'']).trim_start();
+                let rest = rest.strip_prefix(['=', ':'])?.trim_start();
+                leading_number(rest.trim_start_matches(['"', '\'']))
+            }),
+            Field::Group(group, regex) => {
+                let (start, end) = regex.captures(line)?[*group]?;
+                leading_number(line[start..end].trim())
+            }
+        }
+    }
+
+    pub fn keeps(&self, line: &str) -> bool {
+        let Some(value) = self.value_in(line) else {
+            return false;
+        };
+        match self.op {
+            Op::Lt => value < self.value,
+            Op::Le => value <= self.value,
+            Op::Gt => value > self.value,
+            Op::Ge => value >= self.value,
+            Op::Eq => value == self.value,
+            Op::Ne => value != self.value,
+        }
+    }
+}
+
+fn is_key_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// Reads the number at the start of `s`, ignoring units like the `ms` in `812ms`.
+fn leading_number(s: &str) -> Option<f64> {
+    let end = s
+        .char_indices()
+        .find(|&(i, c)| !(c.is_ascii_digit() || c == '.' || (i == 0 && matches!(c, '-' | '+'))))
+        .map_or(s.len(), |(i, _)| i);
+    s[..end].parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_value_fields() {
+        let filter = Where::parse("latency_ms > 500", None).unwrap();
+        assert!(filter.keeps("GET /api latency_ms=812 status=200"));
+        assert!(filter.keeps(r#"{"path":"/","latency_ms": 501.5}"#));
+        assert!(!filter.keeps("GET /api latency_ms=12ms"));
+        assert!(!filter.keeps("GET /api total_latency_ms=900"));
+        assert!(!filter.keeps("GET /api latency_ms=slow"));
+        assert!(!filter.keeps("no field here"));
+
+        assert!(Where::parse("status!=200", None).unwrap().keeps("status=404"));
+    }
+
+    #[test]
+    fn capture_group_fields() {
+        let regex = Regex::new(r"took (\d+)ms", false).unwrap();
+        let filter = Where::parse("$1 <= 100", Some(&regex)).unwrap();
+        assert!(filter.keeps("request took 99ms"));
+        assert!(!filter.keeps("request took 101ms"));
+
+        assert!(Where::parse("$2 < 1", Some(&regex)).is_err());
+        assert!(Where::parse("$1 < 1", None).is_err());
+    }
+
+    #[test]
+    fn invalid_comparisons() {
+        assert!(Where::parse("latency", None).is_err());
+        assert!(Where::parse("latency > fast", None).is_err());
+        assert!(Where::parse("> 5", None).is_err());
+        assert!(Where::parse("a b > 5", None).is_err());
+    }
+}
@@ -0,0 +1,353 @@
+//! Output formats for non-interactive output, selected with `--format-name=NAME`.
+//!
+//! Every format is listed once in `FORMATS`; `--help` and the shell completions
+//! are generated from that list, so a new format only needs a `Formatter` impl
+//! and an entry there.
+
+use std::io::{self, Write};
+
+
+use crate::json::{field, Value};
+use crate::{highlight, Config, LineMatch};
+
+pub trait Formatter: Sync {
+    /// Writes every matched line.
+    fn write(&self, out: &mut dyn Write, matches: &[LineMatch], config: &Config) -> io::Result<()>;
+
+    /// Writes the `--top` summary. Formats without a table shape of their own
+    /// print the aligned count/percentage/value table.
+    fn write_top(&self, out: &mut dyn Write, top: &[(String, usize)], total: usize, config: &Config) -> io::Result<()> {
+        for (value, count) in top {
+            let share = format!("{:>6.2}%", percent(*count, total));
+            let value = config.paint_match(value);
+            writeln!(out, "{count:>8} {share}  {value}")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Format {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub formatter: &'static dyn Formatter,
+    /// Whether `--hash` adds a line fingerprint to this format
+    pub hashes: bool,
+}
+
+pub static FORMATS: &[Format] = &[
+    Format { name: "plain", description: "matched lines, highlighted, without the pager", formatter: &Plain, hashes: false },
+    Format { name: "json", description: "one JSON object per matched line", formatter: &Json, hashes: true },
+    Format { name: "csv", description: "CSV table with a header row", formatter: &Csv, hashes: true },
+    Format { name: "html", description: "standalone HTML page with <mark>ed matches", formatter: &Html, hashes: false },
+    Format { name: "sarif", description: "SARIF 2.1.0 log for code scanning tools", formatter: &Sarif, hashes: true },
+    Format { name: "vimgrep", description: "path:line:column:text, one line per match", formatter: &Vimgrep, hashes: false },
+];
+
+pub fn find(name: &str) -> Option<&'static Format> {
+    FORMATS.iter().find(|format| format.name == name)
+}
+
+/// A stable fingerprint of a matched line.
+///
+/// FNV-1a over the line text with escape sequences and surrounding whitespace
+/// removed, so the same finding hashes the same across runs, toolchains and
+/// re-indentation even when its line number shifts.
+pub fn fingerprint(text: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in text.trim().bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{hash:016x}")
+}
+
+/// Quotes a CSV field when it contains a separator, quote or line break.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn percent(count: usize, total: usize) -> f64 {
+    (count as f64 * 10000.0 / total as f64).round() / 100.0
+}
+
+/// 1-based character column of a byte offset
+fn column(text: &str, byte: usize) -> usize {
+    text[..byte].chars().count() + 1
+}
+
+fn number(n: usize) -> Value {
+    Value::Number(n as f64)
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+pub struct Plain;
+
+impl Formatter for Plain {
+    fn write(&self, out: &mut dyn Write, matches: &[LineMatch], config: &Config) -> io::Result<()> {
+        for m in matches {
+            if config.line_number {
+                let gutter = format!("{:>4}:", m.index + 1);
+                if config.no_color {
+                    write!(out, "{gutter} ")?;
+                } else {
+                    write!(out, "{} ", config.theme.gutter(&gutter))?;
+                }
+            }
+            writeln!(out, "{}", highlight(m, config))?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Json;
+
+impl Formatter for Json {
+    fn write(&self, out: &mut dyn Write, matches: &[LineMatch], config: &Config) -> io::Result<()> {
+        for m in matches {
+            let spans = m.spans.iter().map(|&(start, end)| Value::Array(vec![number(start), number(end)])).collect();
+            let mut fields = vec![
+                field("path", string(&config.file_path)),
+                field("line", number(m.index + 1)),
+                field("text", string(&m.text)),
+                field("matches", Value::Array(spans)),
+            ];
+            if let Some(group) = &m.group {
+                fields.push(field("group", string(group)));
+            }
+            if config.hash {
+                fields.push(field("hash", Value::String(fingerprint(&m.text))));
+            }
+            writeln!(out, "{}", Value::Object(fields))?;
+        }
+        Ok(())
+    }
+
+    fn write_top(&self, out: &mut dyn Write, top: &[(String, usize)], total: usize, _: &Config) -> io::Result<()> {
+        for (value, count) in top {
+            let row = Value::Object(vec![
+                field("value", string(value)),
+                field("count", number(*count)),
+                field("percent", Value::Number(percent(*count, total))),
+            ]);
+            writeln!(out, "{row}")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Csv;
+
+impl Formatter for Csv {
+    fn write(&self, out: &mut dyn Write, matches: &[LineMatch], config: &Config) -> io::Result<()> {
+        if config.hash {
+            writeln!(out, "path,line,text,hash")?;
+        } else {
+            writeln!(out, "path,line,text")?;
+        }
+
+        for m in matches {
+            write!(out, "{},{},{}", csv_field(&config.file_path), m.index + 1, csv_field(&m.text))?;
+            if config.hash {
+                write!(out, ",{}", fingerprint(&m.text))?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn write_top(&self, out: &mut dyn Write, top: &[(String, usize)], total: usize, _: &Config) -> io::Result<()> {
+        writeln!(out, "value,count,percent")?;
+        for (value, count) in top {
+            writeln!(out, "{},{},{}", csv_field(value), count, percent(*count, total))?;
+        }
+        Ok(())
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+pub struct Html;
+
+impl Formatter for Html {
+    fn write(&self, out: &mut dyn Write, matches: &[LineMatch], config: &Config) -> io::Result<()> {
+        writeln!(out, "<!DOCTYPE html>")?;
+        writeln!(out, "<html><head><meta charset=\"utf-8\"><title>minigrep: {}</title>", html_escape(&config.query))?;
+        writeln!(out, "<style>.line{{color:#888;user-select:none}}</style></head><body>")?;
+        writeln!(out, "<h1>{}</h1><pre>", html_escape(&config.file_path))?;
+
+        for m in matches {
+            write!(out, "<span class=\"line\">{:>4}</span> ", m.index + 1)?;
+            let mut last = 0;
+            for &(start, end) in &m.spans {
+                write!(out, "{}<mark>{}</mark>", html_escape(&m.text[last..start]), html_escape(&m.text[start..end]))?;
+                last = end;
+            }
+            writeln!(out, "{}", html_escape(&m.text[last..]))?;
+        }
+
+        writeln!(out, "</pre></body></html>")
+    }
+}
+
+pub struct Sarif;
+
+impl Formatter for Sarif {
+    fn write(&self, out: &mut dyn Write, matches: &[LineMatch], config: &Config) -> io::Result<()> {
+        let results = matches
+            .iter()
+            .map(|m| {
+                let (start, end) = m.spans.first().copied().unwrap_or((0, m.text.len()));
+                let region = Value::Object(vec![
+                    field("startLine", number(m.index + 1)),
+                    field("startColumn", number(column(&m.text, start))),
+                    field("endColumn", number(column(&m.text, end))),
+                ]);
+                let location = Value::Object(vec![field(
+                    "physicalLocation",
+                    Value::Object(vec![
+                        field("artifactLocation", Value::Object(vec![field("uri", string(&config.file_path))])),
+                        field("region", region),
+                    ]),
+                )]);
+                let mut result = vec![
+                    field("ruleId", string("match")),
+                    field("level", string("note")),
+                    field("message", Value::Object(vec![field("text", string(m.text.trim()))])),
+                    field("locations", Value::Array(vec![location])),
+                ];
+                if config.hash {
+                    let hash = Value::Object(vec![field("lineHash/v1", Value::String(fingerprint(&m.text)))]);
+                    result.push(field("partialFingerprints", hash));
+                }
+                Value::Object(result)
+            })
+            .collect();
+
+        let rule = Value::Object(vec![
+            field("id", string("match")),
+            field("shortDescription", Value::Object(vec![field("text", Value::String(format!("Matches '{}'", config.query)))])),
+        ]);
+        let driver = Value::Object(vec![
+            field("name", string("minigrep")),
+            field("version", string(env!("CARGO_PKG_VERSION"))),
+            field("rules", Value::Array(vec![rule])),
+        ]);
+        let run = Value::Object(vec![
+            field("tool", Value::Object(vec![field("driver", driver)])),
+            field("results", Value::Array(results)),
+        ]);
+        let log = Value::Object(vec![
+            field("$schema", string("https://json.schemastore.org/sarif-2.1.0.json")),
+            field("version", string("2.1.0")),
+            field("runs", Value::Array(vec![run])),
+        ]);
+        writeln!(out, "{log}")
+    }
+}
+
+pub struct Vimgrep;
+
+impl Formatter for Vimgrep {
+    fn write(&self, out: &mut dyn Write, matches: &[LineMatch], config: &Config) -> io::Result<()> {
+        for m in matches {
+            // Vim's quickfix columns are byte based
+            let starts: Vec<usize> = if m.spans.is_empty() { vec![0] } else { m.spans.iter().map(|s| s.0).collect() };
+            for start in starts {
+                writeln!(out, "{}:{}:{}:{}", config.file_path, m.index + 1, start + 1, m.text)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::find_matches;
+
+    #[test]
+    fn fingerprint_ignores_position_and_indentation() {
+        assert_eq!(fingerprint("  let x = 1;"), fingerprint("let x = 1;\t"));
+        assert_ne!(fingerprint("let x = 1;"), fingerprint("let x = 2;"));
+        // Pinned so the value stays stable across releases
+        assert_eq!(fingerprint(""), "cbf29ce484222325");
+    }
+
+    #[test]
+    fn json_and_csv_include_hash() {
+        let config = Config {
+            query: "fast".to_string(),
+            file_path: "poem.txt".to_string(),
+            hash: true,
+            ..Default::default()
+        };
+        let (matches, _) = find_matches("safe, \"fast\", productive.", &config);
+        let hash = fingerprint("safe, \"fast\", productive.");
+
+        let mut json = Vec::new();
+        Json.write(&mut json, &matches, &config).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            format!("{{\"path\":\"poem.txt\",\"line\":1,\"text\":\"safe, \\\"fast\\\", productive.\",\"matches\":[[7,11]],\"hash\":\"{hash}\"}}\n")
+        );
+
+        let mut csv = Vec::new();
+        Csv.write(&mut csv, &matches, &config).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            format!("path,line,text,hash\npoem.txt,1,\"safe, \"\"fast\"\", productive.\",{hash}\n")
+        );
+    }
+
+    #[test]
+    fn json_parses_back() {
+        let config = Config { query: "x".to_string(), file_path: "odd \"name\".txt".to_string(), ..Default::default() };
+        let (matches, _) = find_matches("tab\tx \\ \u{1}", &config);
+
+        let mut json = Vec::new();
+        Json.write(&mut json, &matches, &config).unwrap();
+        let value = crate::json::parse(String::from_utf8(json).unwrap().trim()).unwrap();
+        assert_eq!(value.get("text").and_then(Value::as_str), Some("tab\tx \\ \u{1}"));
+        assert_eq!(value.get("path").and_then(Value::as_str), Some("odd \"name\".txt"));
+    }
+
+    #[test]
+    fn vimgrep_and_sarif_locations() {
+        let config = Config {
+            query: "st".to_string(),
+            file_path: "poem.txt".to_string(),
+            ..Default::default()
+        };
+        let (matches, _) = find_matches("Rust:\nTrust me, test.", &config);
+
+        let mut vimgrep = Vec::new();
+        Vimgrep.write(&mut vimgrep, &matches, &config).unwrap();
+        assert_eq!(
+            String::from_utf8(vimgrep).unwrap(),
+            "poem.txt:1:3:Rust:\npoem.txt:2:4:Trust me, test.\npoem.txt:2:13:Trust me, test.\n"
+        );
+
+        let mut sarif = Vec::new();
+        Sarif.write(&mut sarif, &matches[..1], &config).unwrap();
+        let sarif = String::from_utf8(sarif).unwrap();
+        assert!(sarif.contains("\"version\":\"2.1.0\""));
+        assert!(sarif.contains("\"region\":{\"startLine\":1,\"startColumn\":3,\"endColumn\":5}"));
+    }
+
+    #[test]
+    fn every_format_is_registered_once() {
+        for format in FORMATS {
+            assert!(std::ptr::eq(find(format.name).unwrap(), format));
+        }
+        assert!(find("xml").is_none());
+    }
+}
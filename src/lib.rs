@@ -1,16 +1,43 @@
-use std::{collections::HashSet, env, error::Error, fs};
+use std::{collections::{HashMap, HashSet}, env, error::Error, fs};
+use std::path::{Path, PathBuf};
 use colored::Colorize;
 use std::borrow::Cow;
 use strsim::levenshtein;
-use std::io::{stdin, stdout, Write};
+use std::io::{self, stdin, stdout, BufRead, BufReader, Write};
+use std::time::Instant;
 use termion::event::{Event, Key};
 use termion::input::TermRead;
 use termion::raw::IntoRawMode;
 use termion::screen::IntoAlternateScreen;
 use termion::{clear, cursor, terminal_size};
+
+pub mod cancel;
+pub mod cli;
+pub mod codec;
+#[cfg(unix)]
+pub mod daemon;
+pub mod filter;
+pub mod format;
+pub mod json;
+pub mod pattern;
+pub mod root;
+pub mod search;
+pub mod theme;
+pub mod transform;
+
+use filter::Where;
+use format::Formatter;
+use json::{field, Value};
+use pattern::Regex;
+pub use cancel::{CancellationToken, Cancelled, Progress};
+use search::Collector;
+pub use search::{search_bytes, strip_ansi, LineMatch, Matcher, SearchOptions, SearchResults, Stage};
+use theme::{Render, Theme};
+use transform::LineMap;
  
 
 
+#[derive(Default)]
 pub struct Config {
     pub query: String,
     pub file_path: String,
@@ -18,33 +45,143 @@ pub struct Config {
     pub no_color: bool,
     pub line_number: bool,
     pub stats: bool,
+    pub no_alt_screen: bool,
+    pub strip_ansi: bool,
+    /// Non-interactive output format, the pager is used when unset
+    pub format: Option<&'static format::Format>,
+    pub hash: bool,
+    /// Compiled query when `--regex` is set
+    pub regex: Option<Regex>,
+    /// Capture group whose value groups the results
+    pub group_by: Option<usize>,
+    /// Print only the N most frequent matched values
+    pub top: Option<usize>,
+    /// Transformation applied to matched lines before display
+    pub map: Option<LineMap>,
+    /// Print nothing and only report through the exit status whether anything matched
+    pub quiet: bool,
+    /// Stop after this many matching lines
+    pub max_count: Option<usize>,
+    /// Approximate cap in bytes on the memory kept for results
+    pub max_memory: Option<usize>,
+    /// Lines longer than this many bytes are cut before searching
+    pub max_line_length: Option<usize>,
+    /// Numeric comparison matched lines must also pass
+    pub filter: Option<Where>,
+    /// Secondary colors, picked for the terminal background
+    pub theme: Theme,
+    /// How matches are drawn
+    pub render: Render,
+    /// Cut lines wider than the terminal, keeping the first match in view
+    pub truncate: bool,
+    /// Suggest similar words from the file when nothing matched
+    pub suggest: bool,
+    /// Show the most relevant lines first instead of in file order
+    pub rank: bool,
+    /// Page even when the results fit on one screen
+    pub always_page: bool,
+    /// Keep the lines that don't match the query
+    pub invert_match: bool,
+    /// `--then` stages that narrow down the matching lines further
+    pub stages: Vec<Stage>,
+    /// Directory FILE is relative to, `auto` for the nearest project root
+    pub root: Option<String>,
+    /// Where the pager saves tagged lines on exit
+    pub export: Option<String>,
+    /// Draw tabs, trailing spaces and carriage returns as visible glyphs
+    pub show_invisibles: bool,
 }
 
 
-impl Config {
-    pub fn build(args: &[String]) -> Result<Config, String> {
-        if args.len() < 3 {
+/// Where the query comes from when it isn't on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryInput {
+    /// `--query-from-stdin`: the first line piped in
+    Stdin,
+    /// Only a file was given: ask for the query on the terminal
+    Prompt,
+}
+
+/// Reads the query from the process's own stdin, prompting on stderr when it
+/// is a terminal.
+pub fn read_query(input: QueryInput) -> Result<String, String> {
+    let is_tty = termion::is_tty(&stdin());
+    if input == QueryInput::Prompt {
+        if !is_tty {
             return Err("Not enough arguments!".to_string());
         }
+        eprint!("Search for: ");
+    } else if is_tty {
+        return Err("'--query-from-stdin' expects the query to be piped in".to_string());
+    }
 
-        let query = args[1].clone();
-        let file_path = args[2].clone();
+    let mut line = String::new();
+    stdin().read_line(&mut line).map_err(|err| format!("Can't read the query: {err}"))?;
+    let query = line.trim_end_matches('\n').trim_end_matches('\r');
+    if query.is_empty() {
+        return Err("No query given".to_string());
+    }
+    Ok(query.to_string())
+}
+
+impl Config {
+    /// Parses the arguments alone, never reading stdin: a missing query is an
+    /// error. The binary passes `read_query` to `build_with` instead.
+    pub fn build(args: &[String]) -> Result<Config, String> {
+        Config::build_with(args, |input| match input {
+            QueryInput::Prompt => Err("Not enough arguments!".to_string()),
+            QueryInput::Stdin => Err("'--query-from-stdin' needs a way to read the query".to_string()),
+        })
+    }
+
+    /// Like `build`, with `read_query` supplying the query when the arguments
+    /// don't carry one.
+    pub fn build_with(
+        args: &[String],
+        read_query: impl FnOnce(QueryInput) -> Result<String, String>,
+    ) -> Result<Config, String> {
+        let env = env::vars_os().map(|(k, _)| k.to_string_lossy().into_owned());
+        Config::build_with_env(args, env, read_query)
+    }
+
+    /// Like `build_with`, with the names of the set environment variables
+    /// given rather than read from this process, as for a `--client` request.
+    pub fn build_with_env(
+        args: &[String],
+        env: impl IntoIterator<Item = String>,
+        read_query: impl FnOnce(QueryInput) -> Result<String, String>,
+    ) -> Result<Config, String> {
+        let (args, segments) = split_stages(args);
 
         let mut flags = HashSet::new();
-        flags.extend(env::vars().map(|(k, _)| k.to_uppercase()));  
+        flags.extend(env.into_iter().map(|k| k.to_uppercase()));
 
-        // more flags here
-        let allowed_flags: [&str; 4] = ["ignore-case", "no-color", "line-number","stats"];
+        // more flags in cli::FLAGS and cli::VALUE_FLAGS
         let mut cli_flags = HashSet::new();
+        let mut cli_values = HashMap::new();
+        let mut positionals = Vec::new();
+        let mut flags_ended = false;
 
-        for arg in &args[3..] {
-            if let Some(flag) = arg.strip_prefix("--") {
-                if allowed_flags.contains(&flag) {
+        for arg in args.iter().skip(1) {
+            if arg == "--" && !flags_ended {
+                // Everything after `--` is positional, even when it looks like a flag
+                flags_ended = true;
+            } else if flags_ended || (!arg.starts_with("--") && positionals.len() < 2) {
+                if positionals.len() == 2 {
+                    return Err(format!("Unexpected argument '{arg}'. Only QUERY and FILE go without a '--'"));
+                }
+                positionals.push(arg.clone());
+            } else if let Some(flag) = arg.strip_prefix("--") {
+                if cli::is_flag(flag) {
                     cli_flags.insert(flag);
+                } else if let Some((name, value)) = flag.split_once('=').filter(|(name, _)| cli::is_value_flag(name)) {
+                    cli_values.insert(name, value);
+                } else if cli::is_value_flag(flag) {
+                    return Err(format!("Flag '--{flag}' needs a value, as in '--{flag}=VALUE'"));
                 } else {
-                    let suggestion = allowed_flags
-                        .iter()
-                        .min_by_key(|known| levenshtein(flag, known))
+                    let name = flag.split('=').next().unwrap();
+                    let suggestion = cli::flag_names()
+                        .min_by_key(|known| levenshtein(name, known))
                         .unwrap();
 
                     return Err(format!(
@@ -52,16 +189,134 @@ impl Config {
                         flag, suggestion
                     ));
                 }
-            } else {
+            } else if arg.starts_with('-') {
                 return Err(format!("Invalid flag format '{}'. Flags must start with '--'", arg));
+            } else {
+                return Err(format!("Unexpected argument '{arg}'. Only QUERY and FILE go without a '--'"));
             }
         }
 
+        let from_stdin = cli_flags.contains("query-from-stdin");
+        let (query, file_path) = match (positionals.len(), from_stdin) {
+            (1, true) => (read_query(QueryInput::Stdin)?, positionals.remove(0)),
+            (1, false) => (read_query(QueryInput::Prompt)?, positionals.remove(0)),
+            (2, true) => {
+                return Err("'--query-from-stdin' reads the query, so only give the FILE".to_string());
+            }
+            (2, false) => {
+                let file_path = positionals.pop().unwrap();
+                (positionals.pop().unwrap(), file_path)
+            }
+            _ => return Err("Not enough arguments!".to_string()),
+        };
+
         // more flags here
         let ignore_case = flags.contains("IGNORE_CASE") || cli_flags.contains("ignore-case");
         let no_color = flags.contains("NO_COLOR") || cli_flags.contains("no-color");
         let line_number = flags.contains("LINE_NUMBER") || cli_flags.contains("line-number");
         let stats = flags.contains("STATS") || cli_flags.contains("stats");
+        let no_alt_screen = flags.contains("NO_ALT_SCREEN") || cli_flags.contains("no-alt-screen");
+        let strip_ansi = flags.contains("STRIP_ANSI") || cli_flags.contains("strip-ansi");
+        let hash = cli_flags.contains("hash");
+        let quiet = cli_flags.contains("quiet");
+        let truncate = flags.contains("TRUNCATE") || cli_flags.contains("truncate");
+        let suggest = cli_flags.contains("suggest");
+        let rank = cli_flags.contains("rank");
+        let always_page = flags.contains("ALWAYS_PAGE") || cli_flags.contains("always-page");
+        let show_invisibles = cli_flags.contains("show-invisibles");
+        let invert_match = cli_flags.contains("invert-match");
+        let root = cli_values.get("root").map(|root| root.to_string());
+        let export = cli_values.get("export").map(|path| path.to_string());
+
+        let mut format_names: Vec<&str> = ["json", "csv"]
+            .into_iter()
+            .filter(|name| cli_flags.contains(name))
+            .chain(cli_values.get("format-name").copied())
+            .collect();
+        // JSON and CSV in the environment only pick a format when none was passed
+        if format_names.is_empty() {
+            format_names.extend(["json", "csv"].into_iter().filter(|name| flags.contains(&name.to_uppercase())));
+        }
+        let format = match format_names[..] {
+            [] => None,
+            [name] => Some(format::find(name).ok_or_else(|| {
+                let known: Vec<&str> = format::FORMATS.iter().map(|format| format.name).collect();
+                format!("Unknown format '{name}'. Available formats: {}", known.join(", "))
+            })?),
+            _ => return Err("Only one output format can be used, pick one of '--json', '--csv' and '--format-name'".to_string()),
+        };
+        if hash && !format.is_some_and(|format| format.hashes) {
+            let hashing: Vec<&str> = format::FORMATS.iter().filter(|format| format.hashes).map(|format| format.name).collect();
+            return Err(format!("'--hash' only applies to the {} formats", hashing.join(", ")));
+        }
+
+        let regex = if cli_flags.contains("regex") {
+            Some(Regex::new(&query, ignore_case)?)
+        } else {
+            None
+        };
+
+        let stages = segments
+            .iter()
+            .map(|segment| parse_stage(segment, ignore_case))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let group_by = match cli_values.get("group-by") {
+            Some(value) => {
+                let group = parse_number("group-by", value)?;
+                let Some(regex) = &regex else {
+                    return Err("'--group-by' needs a capture group, so it only works with '--regex'".to_string());
+                };
+                let available = regex.group_count();
+                if group == 0 || group > available {
+                    return Err(format!("'--group-by={group}' is out of range, the pattern has {available} capture group(s)"));
+                }
+                Some(group)
+            }
+            None => None,
+        };
+
+        let top = match cli_values.get("top") {
+            Some(value) => match parse_number("top", value)? {
+                0 => return Err("'--top' needs at least one value to show".to_string()),
+                n => Some(n),
+            },
+            None => None,
+        };
+
+        if export.is_some() && (quiet || top.is_some() || format.is_some()) {
+            return Err("'--export' saves lines tagged in the pager, so it can't be used with '--quiet', '--top' or an output format".to_string());
+        }
+
+        let map = match cli_values.get("map") {
+            Some(expr) => Some(LineMap::parse(expr, ignore_case)?),
+            None => None,
+        };
+
+        let max_count = match cli_values.get("max-count") {
+            Some(value) => Some(parse_number("max-count", value)?),
+            None => None,
+        };
+
+        let filter = match cli_values.get("where") {
+            Some(expr) => Some(Where::parse(expr, regex.as_ref())?),
+            None => None,
+        };
+
+        let max_memory = match cli_values.get("max-memory") {
+            Some(value) => Some(parse_size("max-memory", value)?),
+            None => None,
+        };
+
+        let max_line_length = match cli_values.get("max-line-length") {
+            Some(value) => Some(parse_size("max-line-length", value)?),
+            None => None,
+        };
+
+        let theme = Theme::from_name(cli_values.get("theme").copied().unwrap_or("auto"))?;
+        let render = Render::from_name(cli_values.get("render").copied().unwrap_or("ansi"))?;
+        // Markers replace every escape code, including the gutter's and the input's own
+        let no_color = no_color || render == Render::AnsiDebug;
 
         Ok(Config {
             query,
@@ -69,111 +324,856 @@ impl Config {
             ignore_case,
             no_color,
             line_number,
-            stats
+            stats,
+            no_alt_screen,
+            strip_ansi,
+            format,
+            hash,
+            regex,
+            group_by,
+            top,
+            map,
+            quiet,
+            max_count,
+            max_memory,
+            max_line_length,
+            filter,
+            theme,
+            render,
+            truncate,
+            suggest,
+            rank,
+            always_page,
+            show_invisibles,
+            invert_match,
+            stages,
+            root,
+            export,
         })
     }   
 
 }
 
-fn conditional_lowercase<'a>(s: &'a str, ignore_case: bool) -> Cow<'a, str> {
-    if ignore_case {
-        Cow::Owned(s.to_lowercase()) 
-    } else {
-        Cow::Borrowed(s)  
+impl Config {
+    /// The limits `--max-count`, `--max-memory`, `--max-line-length` and `--where` put on a search,
+    /// and whether lines keep their `\r` for `--show-invisibles`
+    pub fn search_options(&self) -> SearchOptions {
+        SearchOptions {
+            max_count: self.max_count,
+            max_memory: self.max_memory,
+            filter: self.filter.clone(),
+            keep_cr: self.show_invisibles,
+            max_line_length: self.max_line_length,
+        }
+    }
+
+    /// Draws a matched piece of text
+    pub fn paint_match(&self, s: &str) -> String {
+        match self.render {
+            Render::AnsiDebug => format!("<<{s}>>"),
+            Render::Ansi if self.no_color => s.to_string(),
+            Render::Ansi => s.red().bold().to_string(),
+        }
+    }
+
+    /// FILE as seen from `cwd`, under the `--root` directory when there is one.
+    pub fn path_in(&self, cwd: &Path) -> Result<PathBuf, String> {
+        let base = match self.root.as_deref() {
+            None => cwd.to_path_buf(),
+            Some("auto") => root::find_root(cwd).ok_or_else(|| {
+                format!("'--root=auto' found no {} above '{}'", root::MARKERS.join(" or "), cwd.display())
+            })?,
+            Some(dir) => cwd.join(dir),
+        };
+        Ok(base.join(&self.file_path))
+    }
+
+    /// FILE as seen from the current directory
+    pub fn path(&self) -> Result<PathBuf, Box<dyn Error>> {
+        match self.root {
+            None => Ok(PathBuf::from(&self.file_path)),
+            Some(_) => Ok(self.path_in(&env::current_dir()?)?),
+        }
+    }
+
+    /// The matcher for the query as configured
+    pub fn matcher(&self) -> Matcher {
+        let matcher = match &self.regex {
+            Some(regex) => Matcher::Regex(regex.clone()),
+            None => Matcher::literal(&self.query, self.ignore_case),
+        };
+        if self.stages.is_empty() && !self.invert_match {
+            return matcher;
+        }
+
+        let first = Stage { matcher, invert: self.invert_match };
+        Matcher::Pipeline([first].into_iter().chain(self.stages.iter().cloned()).collect())
     }
 }
 
+/// Flags that belong to the `--then` stage they follow
+const STAGE_FLAGS: &[&str] = &["--invert-match", "--ignore-case", "--regex"];
 
+/// Splits the arguments at each `--then` before a `--` separator into the main
+/// invocation, still starting with the program name, and one list per stage.
+/// Other flags given after a `--then` apply to the whole search, so they move
+/// to the main invocation.
+fn split_stages(args: &[String]) -> (Vec<String>, Vec<Vec<String>>) {
+    let flags_end = args.iter().position(|arg| arg == "--").unwrap_or(args.len());
+    let Some(first) = args[..flags_end].iter().position(|arg| arg == "--then") else {
+        return (args.to_vec(), Vec::new());
+    };
 
+    let mut main = args[..first].to_vec();
+    let mut stages: Vec<Vec<String>> = Vec::new();
+    let mut stage_flags_ended = false;
+    for (i, arg) in args.iter().enumerate().skip(first) {
+        if arg == "--then" && i < flags_end {
+            stages.push(Vec::new());
+            continue;
+        }
+        if arg == "--" {
+            stage_flags_ended = true;
+        }
+        let global = !stage_flags_ended && arg.starts_with("--") && !STAGE_FLAGS.contains(&arg.as_str());
+        if global {
+            main.push(arg.clone());
+        } else {
+            stages.last_mut().unwrap().push(arg.clone());
+        }
+    }
+    (main, stages)
+}
 
-pub fn search(contents: &str, config: &Config) -> (Vec<String>, Vec<usize>,i32,i32) {
-    let query = conditional_lowercase(&config.query, config.ignore_case);
-    let mut scanned_lines = 0;
-    let mut matched_words = 0;
-    let mut found_indexes = Vec::new();
-
-    let results: Vec<String> = contents
-        .lines()
-        .enumerate()
-        .filter_map(|(index, line)| {
-            scanned_lines += 1;
-
-            let haystack = conditional_lowercase(line, config.ignore_case);
-
-            if haystack.contains(&*query) {
-                found_indexes.push(index);  
-                let highlighted_line = line
-                    .split_whitespace()
-                    .zip(haystack.split_whitespace())
-                    .map(|(original, lowered)| {
-                        if let Some(pos) = lowered.find(&*query) {
-                            matched_words += 1;
-
-
-                            let before = &original[..pos];
-                            let matched = &original[pos..pos + query.len()];
-                            let after = &original[pos + query.len()..];
-
-                            if !config.no_color {
-                                format!("{}{}{}", before, matched.red().bold(), after)
-                            } else {
-                                format!("{}{}{}", before, matched, after)
-                            }
-                        } else {
-                            original.to_string()
-                        }
-                    })
-                    .collect::<Vec<_>>()
-                    .join(" ");
-                
-                Some(highlighted_line)
-            } else {
-                None
+/// Parses one `--then [--invert-match] [--ignore-case] [--regex] [--] QUERY` stage.
+fn parse_stage(args: &[String], ignore_case: bool) -> Result<Stage, String> {
+    let (mut invert, mut ignore_case, mut regex) = (false, ignore_case, false);
+    let mut query = None;
+    let mut flags_ended = false;
+
+    for arg in args {
+        match arg.as_str() {
+            "--" if !flags_ended => flags_ended = true,
+            "--invert-match" if !flags_ended => invert = true,
+            "--ignore-case" if !flags_ended => ignore_case = true,
+            "--regex" if !flags_ended => regex = true,
+            _ if query.is_some() => return Err(format!("Unexpected argument '{arg}', a '--then' stage takes one query")),
+            _ => query = Some(arg),
+        }
+    }
+
+    let query = query.ok_or("'--then' needs a query to search the results for")?;
+    let matcher = if regex { Matcher::regex(query, ignore_case)? } else { Matcher::literal(query, ignore_case) };
+    Ok(Stage { matcher, invert })
+}
+
+fn parse_number(flag: &str, value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value '{value}' for '--{flag}', expected a number"))
+}
+
+/// Parses sizes like `4096`, `512K`, `64M` or `1G` (powers of 1024).
+fn parse_size(flag: &str, value: &str) -> Result<usize, String> {
+    let upper = value.to_ascii_uppercase();
+    let digits = upper.strip_suffix('B').unwrap_or(&upper);
+    let (digits, unit) = match digits.char_indices().last() {
+        Some((i, 'K')) => (&digits[..i], 1 << 10),
+        Some((i, 'M')) => (&digits[..i], 1 << 20),
+        Some((i, 'G')) => (&digits[..i], 1 << 30),
+        _ => (digits, 1),
+    };
+
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("Invalid size '{value}' for '--{flag}', expected something like 4096, 512K, 64M or 1G"))
+}
+
+
+/// What `find_first_match` saw before it stopped.
+pub struct FirstMatch {
+    /// 0-based index of the first matching line, if any
+    pub index: Option<usize>,
+    pub scanned_lines: usize,
+    pub bytes_read: u64,
+    /// Size of the file, 0 when it is compressed
+    pub total_bytes: u64,
+}
+
+/// Streams the file line by line and stops reading at the first matching line.
+///
+/// This is the path for `--quiet`, which only needs to know whether anything
+/// matched, so the rest of the file is never read. Cancelling `token` stops it
+/// with a `Cancelled` error.
+pub fn find_first_match(config: &Config, token: &CancellationToken) -> Result<FirstMatch, Box<dyn Error>> {
+    let options = SearchOptions { max_count: Some(1), ..config.search_options() };
+    let (results, progress) = scan_file(&config.path()?, &config.matcher(), &options, token, &mut |_| {})?;
+
+    Ok(FirstMatch {
+        index: results.matches.first().map(|m| m.index),
+        scanned_lines: progress.scanned_lines,
+        bytes_read: progress.bytes_read,
+        total_bytes: progress.total_bytes,
+    })
+}
+
+/// Lines between two progress reports
+const PROGRESS_INTERVAL: usize = 4096;
+
+/// Reads a line into `buf` like `read_until(b'\n')`, without the newline, but
+/// keeps at most `limit` bytes of it and skips the rest, so one huge line can't
+/// take all memory. Returns the bytes consumed and whether the line was cut.
+pub(crate) fn read_line_capped(reader: &mut impl BufRead, buf: &mut Vec<u8>, limit: usize) -> io::Result<(usize, bool)> {
+    let (mut read, mut cut) = (0, false);
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if available.is_empty() {
+            break;
+        }
+
+        let newline = available.iter().position(|&b| b == b'\n');
+        let content = &available[..newline.unwrap_or(available.len())];
+        let room = limit - buf.len();
+        if content.len() > room {
+            cut = true;
+        }
+        buf.extend_from_slice(&content[..content.len().min(room)]);
+
+        let used = newline.map_or(available.len(), |i| i + 1);
+        reader.consume(used);
+        read += used;
+        if newline.is_some() {
+            break;
+        }
+    }
+
+    // Don't leave half a character where the line was cut
+    if cut && let Err(err) = std::str::from_utf8(buf) && err.error_len().is_none() {
+        buf.truncate(err.valid_up_to());
+    }
+    Ok((read, cut))
+}
+
+/// Streams `path` line by line, checking `token` before every line and reporting
+/// progress every `PROGRESS_INTERVAL` lines and once more at the end.
+pub(crate) fn scan_file(
+    path: &Path,
+    matcher: &Matcher,
+    options: &SearchOptions,
+    token: &CancellationToken,
+    on_progress: &mut dyn FnMut(&Progress),
+) -> Result<(SearchResults, Progress), Box<dyn Error>> {
+    let (input, codec) = codec::open(path)?;
+    // The decompressed size isn't known up front
+    let total_bytes = if codec.is_some() { 0 } else { fs::metadata(path)?.len() };
+    let mut progress = Progress { total_bytes, ..Default::default() };
+    let mut reader = BufReader::new(input);
+    let mut collector = Collector::new(options);
+    let mut buf = Vec::new();
+    let line_limit = options.line_limit();
+
+    while !collector.is_done() {
+        if token.is_cancelled() {
+            return Err(Box::new(Cancelled));
+        }
+
+        buf.clear();
+        let (read, cut) = read_line_capped(&mut reader, &mut buf, line_limit)?;
+        if read == 0 {
+            break;
+        }
+        progress.bytes_read += read as u64;
+        if cut {
+            collector.cut_line();
+        }
+
+        let line = String::from_utf8_lossy(&buf);
+        let line = search::trim_line_end(&line, options.keep_cr);
+        if let Some(m) = matcher.match_line(progress.scanned_lines, line)
+            && collector.push(m)
+        {
+            progress.matched_lines += 1;
+        }
+        progress.scanned_lines += 1;
+
+        if progress.scanned_lines.is_multiple_of(PROGRESS_INTERVAL) {
+            on_progress(&progress);
+        }
+    }
+
+    on_progress(&progress);
+    Ok((collector.finish(progress.scanned_lines), progress))
+}
+
+/// Searches the configured file without displaying anything.
+///
+/// Meant for running on a background thread: `progress` is called as the file is
+/// read, and cancelling `token` stops the search with a `Cancelled` error.
+pub fn run_with_cancel(
+    config: &Config,
+    token: &CancellationToken,
+    mut progress: impl FnMut(&Progress),
+) -> Result<SearchResults, Box<dyn Error>> {
+    let (results, _) = scan_file(&config.path()?, &config.matcher(), &config.search_options(), token, &mut progress)?;
+    Ok(results)
+}
+
+/// Finds every matching line. Matching always runs against the text with
+/// escape sequences stripped, so colored input matches like plain input.
+pub fn find_matches(contents: &str, config: &Config) -> (Vec<LineMatch>, i32) {
+    let results = search::search_str(contents, &config.matcher(), &config.search_options());
+
+    (results.matches, results.scanned_lines as i32)
+}
+
+/// Groups matched lines by the value of the `--group-by` capture in their first match.
+///
+/// Groups keep the order in which their first line appeared. Lines where the
+/// group didn't participate end up in a group with no name.
+pub fn group_matches(matches: Vec<LineMatch>, config: &Config) -> Vec<(Option<String>, Vec<LineMatch>)> {
+    let (Some(regex), Some(group)) = (&config.regex, config.group_by) else {
+        return vec![(None, matches)];
+    };
+
+    let mut groups: Vec<(Option<String>, Vec<LineMatch>)> = Vec::new();
+    for mut m in matches {
+        let key = regex
+            .captures(&m.text)
+            .and_then(|caps| caps[group])
+            .map(|(start, end)| m.text[start..end].to_string());
+
+        m.group = key.clone();
+        match groups.iter_mut().find(|(name, _)| *name == key) {
+            Some((_, lines)) => lines.push(m),
+            None => groups.push((key, vec![m])),
+        }
+    }
+    groups
+}
+
+/// How far a line's best match is from being a whole word: 0 when a match is
+/// a word by itself, otherwise the edit distance between the match and the
+/// word around it. Lower is more relevant.
+fn word_distance(m: &LineMatch) -> usize {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    m.spans
+        .iter()
+        .map(|&(start, end)| {
+            let word_start = m.text[..start].rfind(|c| !is_word(c)).map_or(0, |i| i + 1);
+            let word_end = m.text[end..].find(|c| !is_word(c)).map_or(m.text.len(), |i| end + i);
+            levenshtein(&m.text[word_start..word_end].to_lowercase(), &m.text[start..end].to_lowercase())
+        })
+        .min()
+        .unwrap_or(usize::MAX)
+}
+
+/// Orders lines by relevance for `--rank`: whole-word matches first, then
+/// matches inside shorter words, then lines with more matches. Ties keep file order.
+pub fn rank_matches(mut matches: Vec<LineMatch>, config: &Config) -> Vec<LineMatch> {
+    if config.rank {
+        matches.sort_by_cached_key(|m| (word_distance(m), std::cmp::Reverse(m.spans.len())));
+    }
+    matches
+}
+
+/// Applies the `--map` transformation, dropping lines it has nothing to keep for.
+///
+/// Spans are looked up again in the transformed text so whatever part of the
+/// match survived is still highlighted.
+pub fn map_matches(matches: Vec<LineMatch>, config: &Config) -> Vec<LineMatch> {
+    let Some(map) = &config.map else {
+        return matches;
+    };
+    let matcher = config.matcher();
+
+    matches
+        .into_iter()
+        .filter_map(|m| {
+            let text = map.apply(&m.text)?.to_string();
+            let spans = matcher.spans(&text).unwrap_or_default();
+            Some(LineMatch { text, raw: None, spans, ..m })
+        })
+        .collect()
+}
+
+/// The `n` most frequent matched values along with the total number of values.
+///
+/// A value is the `--group-by` capture when grouping, the transformed line
+/// with `--map`, and the matched text
+/// otherwise (lowercased with `--ignore-case` so different spellings count together).
+/// Ties keep the order in which values first appeared.
+pub fn top_values(matches: &[LineMatch], config: &Config, n: usize) -> (Vec<(String, usize)>, usize) {
+    let mut counts: Vec<(String, usize)> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    let mut total = 0;
+
+    let mut count = |value: String| {
+        total += 1;
+        match positions.get(&value) {
+            Some(&i) => counts[i].1 += 1,
+            None => {
+                positions.insert(value.clone(), counts.len());
+                counts.push((value, 1));
             }
+        }
+    };
+
+    for m in matches {
+        if config.map.is_some() {
+            count(m.text.clone());
+            continue;
+        }
+        if config.group_by.is_some() {
+            if let Some(group) = &m.group {
+                count(group.clone());
+            }
+            continue;
+        }
+        for &(start, end) in &m.spans {
+            let value = &m.text[start..end];
+            count(if config.ignore_case { value.to_lowercase() } else { value.to_string() });
+        }
+    }
+
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    counts.truncate(n);
+    (counts, total)
+}
+
+/// Cuts a line down to `width` characters around its first match.
+///
+/// The window is centered on the first match so it stays visible however far
+/// into the line it is, with `…` marking the cut ends. Spans are clipped and
+/// shifted to the new text.
+pub fn truncate_match(m: &LineMatch, width: usize) -> LineMatch {
+    let chars: Vec<(usize, char)> = m.text.char_indices().collect();
+    let total = chars.len();
+    if total <= width || width == 0 {
+        return m.clone();
+    }
+
+    let char_at = |byte: usize| chars.partition_point(|&(b, _)| b < byte);
+    let byte_at = |pos: usize| chars.get(pos).map_or(m.text.len(), |&(b, _)| b);
+
+    let (start, match_start) = match m.spans.first() {
+        Some(&(s, e)) => {
+            let (s, e) = (char_at(s), char_at(e));
+            ((s + e).div_ceil(2).saturating_sub(width / 2).min(total - width), s)
+        }
+        None => (0, 0),
+    };
+
+    // The characters shown from `start` on, with ellipses where the line is cut.
+    // They take up a column each but never the last character that would be left.
+    let fit = |start: usize| {
+        let prefix = start > 0 && width > 1;
+        let mut end = (start + width - prefix as usize).min(total);
+        let suffix = end < total && end - start > 1;
+        if suffix {
+            end -= 1;
+        }
+        (start, end, prefix, suffix)
+    };
+    // The leading ellipsis takes the place of the window's first character
+    let (mut start, mut end, mut prefix, mut suffix) = fit(if start > 0 { start + 1 } else { 0 });
+    // A match too wide for the window, or pushed out by the ellipses, starts it
+    if match_start < start || match_start >= end {
+        (start, end, prefix, suffix) = fit(match_start);
+    }
+
+    let (from, to) = (byte_at(start), byte_at(end));
+    let lead = if prefix { "…" } else { "" };
+    let trail = if suffix { "…" } else { "" };
+    let shift = |pos: usize| pos - from + lead.len();
+
+    let spans = m
+        .spans
+        .iter()
+        .filter(|&&(s, e)| e > from && s < to)
+        .map(|&(s, e)| (shift(s.max(from)), shift(e.min(to))))
+        .collect();
+
+    let text = format!("{lead}{}{trail}", &m.text[from..to]);
+    debug_assert!(text.chars().count() <= width);
+    LineMatch {
+        text,
+        raw: None,
+        spans,
+        ..m.clone()
+    }
+}
+
+/// Renders a matched line for display, highlighting every span.
+///
+/// The input's own colors are kept unless `--strip-ansi` or `--no-color` is set.
+pub fn highlight(m: &LineMatch, config: &Config) -> String {
+    // Where the line's trailing whitespace starts, for `--show-invisibles`
+    let trailing = m.text.trim_end_matches([' ', '\t', '\r']).len();
+
+    let (line, spans, trailing): (&str, Cow<[(usize, usize)]>, usize) = match &m.raw {
+        Some(raw) if !config.strip_ansi && !config.no_color => {
+            let (_, offsets) = strip_ansi(raw).unwrap();
+            let spans = m
+                .spans
+                .iter()
+                .map(|&(start, end)| {
+                    let at = |pos: usize| offsets.get(pos).copied().unwrap_or(raw.len());
+                    if start == end { (at(start), at(start)) } else { (at(start), offsets[end - 1] + 1) }
+                })
+                .collect();
+            (raw, Cow::Owned(spans), offsets.get(trailing).copied().unwrap_or(raw.len()))
+        }
+        _ => (&m.text, Cow::Borrowed(&m.spans), trailing),
+    };
+
+    let visible = |start: usize, end: usize| -> Cow<str> {
+        if config.show_invisibles {
+            Cow::Owned(show_invisibles(&line[start..end], start, trailing))
+        } else {
+            Cow::Borrowed(&line[start..end])
+        }
+    };
+
+    let mut out = String::with_capacity(line.len());
+    let mut last = 0;
+    for &(start, end) in spans.iter() {
+        out.push_str(&visible(last, start));
+        out.push_str(&config.paint_match(&visible(start, end)));
+        last = end;
+    }
+    out.push_str(&visible(last, line.len()));
+    out
+}
+
+/// Swaps tabs for `→`, carriage returns for `␍` and spaces from `trailing` on
+/// for `·`. `at` is where `segment` starts in its line.
+fn show_invisibles(segment: &str, at: usize, trailing: usize) -> String {
+    segment
+        .char_indices()
+        .map(|(i, c)| match c {
+            '\t' => '→',
+            '\r' => '␍',
+            ' ' if at + i >= trailing => '·',
+            c => c,
         })
+        .collect()
+}
+
+/// The `--suggest` hint for a search that found nothing in `haystack`.
+///
+/// Regular expressions get no hint, there is no word to compare them to.
+pub fn suggestion(input: impl BufRead, config: &Config) -> io::Result<Option<String>> {
+    if config.regex.is_some() {
+        return Ok(None);
+    }
+    let words = search::suggest(input, &config.query, 3, config.search_options().line_limit())?;
+    if words.is_empty() {
+        return Ok(None);
+    }
+
+    let words: Vec<String> = words
+        .iter()
+        .map(|(word, hits)| format!("'{word}' ({hits} {})", if *hits == 1 { "hit" } else { "hits" }))
         .collect();
+    Ok(Some(format!("No results for '{}'. Did you mean {}?", config.query, words.join(", "))))
+}
+
+pub fn search(contents: &str, config: &Config) -> (Vec<String>, Vec<usize>,i32,i32) {
+    let (matches, scanned_lines) = find_matches(contents, config);
+    let matched_words = matches.iter().map(|m| m.spans.len() as i32).sum();
+
+    let results = matches.iter().map(|m| highlight(m, config)).collect();
+    let found_indexes = matches.iter().map(|m| m.index).collect();
 
     (results, found_indexes,scanned_lines,matched_words)
 }
 
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let contents = fs::read_to_string(&config.file_path)?;
-    let (res, found, scanned_lines, matched_words) = search(&contents, &config);
+/// The arguments of a `minigrep check QUERY FILE [FLAGS]` invocation without
+/// the subcommand, or `None` when `args` ask for a normal search. `--check`
+/// anywhere before `--` does the same, so `-- check FILE` searches for "check".
+pub fn check_args(args: &[String]) -> Option<Vec<String>> {
+    if args.get(1).is_some_and(|arg| arg == "check") {
+        let mut args = args.to_vec();
+        args.remove(1);
+        return Some(args);
+    }
+    args.iter().take_while(|arg| *arg != "--").any(|arg| arg == "--check").then(|| args.to_vec())
+}
+
+/// Describes what a search with `config` would do, for `minigrep check`.
+///
+/// Building the `Config` already validated the flags and the pattern, so this
+/// only has to make sure the file can be opened. Nothing is searched.
+pub fn check(config: &Config) -> Result<String, Box<dyn Error>> {
+    let path = config.path()?;
+    let metadata = fs::metadata(&path).map_err(|err| format!("Can't read '{}': {err}", path.display()))?;
+    if !metadata.is_file() {
+        return Err(format!("'{}' is not a regular file", path.display()).into());
+    }
+    fs::File::open(&path).map_err(|err| format!("Can't read '{}': {err}", path.display()))?;
+
+    let mut matching = vec![match &config.regex {
+        Some(regex) => format!("regex with {} capture group(s)", regex.group_count()),
+        None => "literal".to_string(),
+    }];
+    if config.ignore_case {
+        matching.push("ignoring case".to_string());
+    }
+    if config.invert_match {
+        matching.push("inverted".to_string());
+    }
+
+    let output = if config.quiet {
+        "none, exit status only".to_string()
+    } else if let Some(n) = config.top {
+        format!("top {n} values")
+    } else if let Some(format) = config.format {
+        format!("{} format", format.name)
+    } else {
+        "interactive pager".to_string()
+    };
+
+    let mut report = format!("Query: {:?} ({})\n", config.query, matching.join(", "));
+    match codec::detect(&path)? {
+        Some(codec) => report.push_str(&format!("File: {} ({} bytes, {})\n", path.display(), metadata.len(), codec.name)),
+        None => report.push_str(&format!("File: {} ({} bytes)\n", path.display(), metadata.len())),
+    }
+    report.push_str(&format!("Output: {output}\n"));
+    if let Some(group) = config.group_by {
+        report.push_str(&format!("Grouped by: capture group {group}\n"));
+    }
+    if !config.stages.is_empty() {
+        report.push_str(&format!("Then: {} more stage(s)\n", config.stages.len()));
+    }
+    if let Some(n) = config.max_count {
+        report.push_str(&format!("Max count: {n} lines\n"));
+    }
+    if let Some(bytes) = config.max_memory {
+        report.push_str(&format!("Max memory: {bytes} bytes\n"));
+    }
+    Ok(report)
+}
+
+/// Runs the search and shows the results. Returns whether anything matched.
+///
+/// Cancelling `token` stops the search early with a `Cancelled` error.
+pub fn run(config: Config, token: &CancellationToken) -> Result<bool, Box<dyn Error>> {
+    if config.quiet {
+        return run_quiet(&config, token);
+    }
+
+    let results = run_with_cancel(&config, token, |_| {})?;
+    let rendered = render(results, &config, &mut stdout().lock(), &mut io::stderr())?;
+
+    match rendered {
+        Rendered::Written(false) if config.suggest => {
+            if let Some(hint) = suggestion(BufReader::new(codec::open(&config.path()?)?.0), &config)? {
+                eprintln!("{hint}");
+            }
+            Ok(false)
+        }
+        Rendered::Written(matched) => Ok(matched),
+        Rendered::Paged(res, found, texts) => {
+            let on_terminal = termion::is_tty(&stdout());
+            let fits = terminal_size().is_ok_and(|size| fits_on_screen(&res, &found, &config, size));
+            // Lines are tagged for `--export` in the pager, so short results get one too
+            if config.always_page || (on_terminal && (!fits || config.export.is_some())) {
+                paginate(&res, &found, &texts, &config)?;
+            } else {
+                if config.export.is_some() {
+                    eprintln!("Nothing exported: lines are tagged for '--export' in the pager, which needs a terminal");
+                }
+                print_rows(&mut stdout().lock(), &res, &found, &config)?;
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// What `render` left for the caller to show.
+pub enum Rendered {
+    /// Everything was written; whether anything matched
+    Written(bool),
+    /// Rows for the interactive pager along with their line indexes and, with
+    /// `--export`, the plain text of the line each row shows
+    Paged(Vec<String>, Vec<Option<usize>>, Vec<Option<String>>),
+}
+
+/// Shows the results of a finished search as configured.
+///
+/// Statistics go to `diag` when `out` carries a format meant for parsing. Results
+/// for the pager are handed back rather than written, as only the caller knows
+/// whether there is a terminal to page on.
+pub fn render(results: SearchResults, config: &Config, out: &mut dyn Write, diag: &mut dyn Write) -> Result<Rendered, Box<dyn Error>> {
+    if results.long_lines > 0 {
+        // A single line over the memory budget is cut to it too
+        let flag = match (config.max_line_length, config.max_memory) {
+            (Some(length), Some(memory)) if memory < length => "--max-memory",
+            (None, _) => "--max-memory",
+            _ => "--max-line-length",
+        };
+        writeln!(
+            diag,
+            "Warning: {} line(s) longer than {flag} were cut before searching",
+            results.long_lines
+        )?;
+    }
+    if results.dropped_lines > 0 {
+        writeln!(
+            diag,
+            "Results truncated: kept the first {} of {} matching lines to stay under --max-memory",
+            results.matches.len(),
+            results.matched_lines()
+        )?;
+    }
+    let (matches, scanned_lines) = (results.matches, results.scanned_lines);
+    let matched_words: usize = matches.iter().map(|m| m.spans.len()).sum();
+    let matched_lines = matches.len();
+    let structured = config.format.is_some();
+    let groups: Vec<_> = group_matches(matches, config)
+        .into_iter()
+        .map(|(name, lines)| (name, map_matches(rank_matches(lines, config), config)))
+        .collect();
 
     if config.stats {
-        println!("Matching lines: {}, Matching words: {}, Lines Scanned: {}", 
-                 res.len(), matched_words, scanned_lines);
+        let mut stats = format!("Matching lines: {}, Matching words: {}, Lines Scanned: {}", 
+                 matched_lines, matched_words, scanned_lines);
+        if config.group_by.is_some() {
+            for (name, lines) in &groups {
+                stats.push_str(&format!("\n  Group {}: {} line(s)", group_label(name), lines.len()));
+            }
+        }
+        // Keep structured output parseable
+        if structured {
+            writeln!(diag, "{stats}")?;
+        } else {
+            writeln!(out, "{stats}")?;
+        }
     }
-    
-    if res.is_empty() {
-        println!("No results found.");
-        return Ok(());
+
+    let matches: Vec<LineMatch> = groups.iter().flat_map(|(_, lines)| lines).cloned().collect();
+
+    if let Some(n) = config.top {
+        let (top, total) = top_values(&matches, config, n);
+        match config.format {
+            Some(format) => format.formatter.write_top(out, &top, total, config)?,
+            None if top.is_empty() => writeln!(out, "No results found.")?,
+            None => format::Plain.write_top(out, &top, total, config)?,
+        }
+        return Ok(Rendered::Written(!matches.is_empty()));
+    }
+
+    if let Some(format) = config.format {
+        format.formatter.write(out, &matches, config)?;
+        return Ok(Rendered::Written(!matches.is_empty()));
     }
     
-    // Use pagination for displaying results
-    paginate(&res, &found, &config)?;
+    if matches.is_empty() {
+        writeln!(out, "No results found.")?;
+        return Ok(Rendered::Written(false));
+    }
 
-    Ok(())
+    let mut res = Vec::new();
+    let mut found = Vec::new();
+    let mut texts = Vec::new();
+    let exporting = config.export.is_some();
+    // Room for `--truncate`, beside the widest gutter any line gets
+    let width = config.truncate.then(|| {
+        let gutter = match matches.iter().map(|m| m.index).max() {
+            Some(last) if config.line_number => format!("| {:>3} | ", last + 1).len(),
+            _ => 0,
+        };
+        terminal_size().map_or(80, |(width, _)| width as usize).saturating_sub(PAGER_PREFIX + gutter)
+    });
+    for (name, lines) in &groups {
+        if config.group_by.is_some() {
+            let heading = format!("== {} ({} line(s)) ==", group_label(name), lines.len());
+            res.push(if config.no_color { heading } else { config.theme.heading(&heading).to_string() });
+            found.push(None);
+            if exporting {
+                texts.push(None);
+            }
+        }
+        for m in lines {
+            match width {
+                Some(width) => res.push(highlight(&truncate_match(m, width), config)),
+                None => res.push(highlight(m, config)),
+            }
+            found.push(Some(m.index));
+            if exporting {
+                texts.push(Some(m.text.clone()));
+            }
+        }
+    }
+
+    Ok(Rendered::Paged(res, found, texts))
+}
+
+fn run_quiet(config: &Config, token: &CancellationToken) -> Result<bool, Box<dyn Error>> {
+    let started = Instant::now();
+    let first = find_first_match(config, token)?;
+    let matched = first.index.is_some();
+
+    if config.stats {
+        // Compressed input has no size to compare against
+        let read = match first.total_bytes {
+            0 => format!("{}", first.bytes_read),
+            size => format!("{} of {} ({:.1}%)", first.bytes_read, size, first.bytes_read as f64 * 100.0 / size as f64),
+        };
+        eprintln!(
+            "Matched: {}, Lines Scanned: {}, Bytes read: {}, Time: {:.2?}",
+            matched,
+            first.scanned_lines,
+            read,
+            started.elapsed()
+        );
+    }
+
+    Ok(matched)
+}
+
+fn group_label(name: &Option<String>) -> String {
+    match name {
+        Some(name) => format!("'{name}'"),
+        None => "(no capture)".to_string(),
+    }
 }
 
  
 
 pub fn paginate(
     results: &[String],
-    indexes: &[usize],
+    indexes: &[Option<usize>],
+    texts: &[Option<String>],
     config: &Config,
 ) -> Result<(), Box<dyn Error>> {
-    let mut screen = stdout().into_raw_mode()?.into_alternate_screen()?;
+    let raw = stdout().into_raw_mode()?;
+
+    // Render in the main screen buffer when asked to, so the pages stay in scrollback
+    let mut screen: Box<dyn Write> = if config.no_alt_screen {
+        Box::new(raw)
+    } else {
+        Box::new(raw.into_alternate_screen()?)
+    };
     
     // Get terminal dimensions
     let (width, height) = terminal_size()?;
     let page_height = height.saturating_sub(3) as usize;  
     
-    let mut current_offset = 0;
+    let mut view = Viewport { offset: 0, cursor: 0, page_height, width };
     let total_lines = results.len();
+    // Triage tags set with 1-3, one per row
+    let mut tags: Vec<Option<u8>> = vec![None; total_lines];
     
     // Initial render
-    render_page(&mut screen, results, indexes, config, current_offset, page_height, width, total_lines)?;
+    render_page(&mut screen, results, indexes, &tags, config, &view)?;
     
     // Handle input events
     let stdin = stdin();
@@ -182,38 +1182,49 @@ pub fn paginate(
             // Exit on Escape or Ctrl+C
             Event::Key(Key::Esc) | Event::Key(Key::Ctrl('c')) => break,
             
-            // Scroll up
+            // Move up
             Event::Key(Key::Up) | Event::Key(Key::Char('k')) => {
-                if current_offset > 0 {
-                    current_offset -= 1;
-                }
+                view.cursor = view.cursor.saturating_sub(1);
             },
             
-            // Scroll down
-            Event::Key(Key::Down) | Event::Key(Key::Char('j')) | Event::Key(Key::Char('\n')) => {
-                if current_offset + page_height < total_lines {
-                    current_offset += 1;
-                }
+            // Move down
+            Event::Key(Key::Down) | Event::Key(Key::Char('j')) | Event::Key(Key::Char('\n'))
+                if view.cursor + 1 < total_lines => {
+                view.cursor += 1;
             },
             
             // Page up
             Event::Key(Key::PageUp) => {
-                current_offset = current_offset.saturating_sub(page_height);
+                view.offset = view.offset.saturating_sub(page_height);
+                view.cursor = view.cursor.saturating_sub(page_height);
             },
             
             // Page down
             Event::Key(Key::PageDown) | Event::Key(Key::Char(' ')) => {
-                current_offset = (current_offset + page_height).min(total_lines.saturating_sub(page_height));
+                view.offset = (view.offset + page_height).min(total_lines.saturating_sub(page_height));
+                view.cursor = (view.cursor + page_height).min(total_lines.saturating_sub(1));
             },
             
             // Home key - go to top
             Event::Key(Key::Home) => {
-                current_offset = 0;
+                view.offset = 0;
+                view.cursor = 0;
             },
             
             // End key - go to bottom
             Event::Key(Key::End) => {
-                current_offset = total_lines.saturating_sub(page_height);
+                view.offset = total_lines.saturating_sub(page_height);
+                view.cursor = total_lines.saturating_sub(1);
+            },
+
+            // Tag the current line, pressing the same key again untags it
+            Event::Key(Key::Char(key @ '1'..='3')) if indexes.get(view.cursor).is_some_and(Option::is_some) => {
+                let tag = key as u8 - b'0';
+                let current = &mut tags[view.cursor];
+                *current = if *current == Some(tag) { None } else { Some(tag) };
+            },
+            Event::Key(Key::Char('0')) if view.cursor < total_lines => {
+                tags[view.cursor] = None;
             },
      
             
@@ -221,33 +1232,175 @@ pub fn paginate(
         }
         
         // Re-render the page after each event
-        render_page(&mut screen, results, indexes, config, current_offset, page_height, width, total_lines)?;
+        view.follow_cursor();
+        render_page(&mut screen, results, indexes, &tags, config, &view)?;
     }
     
+    // Leave the last page visible below the footer when rendering in the main screen
+    if config.no_alt_screen {
+        write!(screen, "{}\r\n", cursor::Goto(1, (page_height + 3) as u16))?;
+    }
+
     // Restore cursor before exiting
     write!(screen, "{}", cursor::Show)?;
     screen.flush()?;
+    // Back on the normal screen before reporting on the tags
+    drop(screen);
+    stdout().flush()?;
+
+    let tagged = tags.iter().flatten().count();
+    match &config.export {
+        Some(path) if tagged > 0 => {
+            let written = export_tags(path, indexes, texts, &tags, config)?;
+            eprintln!("Exported {tagged} tagged line(s) to {}", written.join(", "));
+        }
+        None if tagged > 0 => eprintln!("{tagged} line(s) tagged, pass '--export=PATH' to save them"),
+        _ => {}
+    }
     
     Ok(())
 }
 
-fn render_page<W: Write>(
-    screen: &mut W,
-    results: &[String],
-    indexes: &[usize],
+/// Saves the rows tagged in the pager, by the line number and text each row
+/// shows. A `path` ending in `.json` gets a JSON report of every tag, anything
+/// else one `PATH.N` file per tag used, with a `LINE:TEXT` row per tagged line.
+/// Returns the files written.
+pub fn export_tags(
+    path: &str,
+    indexes: &[Option<usize>],
+    texts: &[Option<String>],
+    tags: &[Option<u8>],
     config: &Config,
+) -> io::Result<Vec<String>> {
+    let tagged = |tag: u8| {
+        indexes.iter().zip(texts).zip(tags).filter_map(move |((index, text), t)| {
+            let (index, text) = index.zip(text.clone())?;
+            (*t == Some(tag)).then_some((index + 1, text))
+        })
+    };
+    let used: Vec<u8> = (1..=3).filter(|tag| tags.contains(&Some(*tag))).collect();
+
+    if path.ends_with(".json") {
+        let sets = used
+            .iter()
+            .map(|&tag| {
+                let lines = tagged(tag)
+                    .map(|(line, text)| {
+                        Value::Object(vec![
+                            field("line", Value::Number(line as f64)),
+                            field("text", Value::String(text)),
+                        ])
+                    })
+                    .collect();
+                (tag.to_string(), Value::Array(lines))
+            })
+            .collect();
+        let report = Value::Object(vec![
+            field("file", Value::String(config.file_path.clone())),
+            field("query", Value::String(config.query.clone())),
+            field("tags", Value::Object(sets)),
+        ]);
+        fs::write(path, format!("{report}\n"))?;
+        return Ok(vec![path.to_string()]);
+    }
+
+    let mut written = Vec::new();
+    for tag in used {
+        let file = format!("{path}.{tag}");
+        let mut out = io::BufWriter::new(fs::File::create(&file)?);
+        for (line, text) in tagged(tag) {
+            writeln!(out, "{line}:{text}")?;
+        }
+        out.flush()?;
+        written.push(file);
+    }
+    Ok(written)
+}
+
+/// Columns the pager's cursor and tag markers take before each row
+const PAGER_PREFIX: usize = 3;
+
+/// The slice of results currently shown by the pager.
+struct Viewport {
     offset: usize,
+    /// The row tag keys apply to
+    cursor: usize,
     page_height: usize,
     width: u16,
-    total_lines: usize
+}
+
+impl Viewport {
+    /// Scrolls just enough to keep the cursor on the page.
+    fn follow_cursor(&mut self) {
+        if self.cursor < self.offset {
+            self.offset = self.cursor;
+        } else if self.cursor >= self.offset + self.page_height {
+            self.offset = (self.cursor + 1).saturating_sub(self.page_height);
+        }
+    }
+}
+
+/// The pager's line-number gutter, without its trailing space
+fn row_gutter(index: Option<usize>, config: &Config) -> Option<String> {
+    match (config.line_number, index) {
+        (true, Some(index)) => Some(format!("| {:>3} |", index + 1)),
+        _ => None,
+    }
+}
+
+/// Writes one pager row, with its gutter when line numbers are on.
+fn write_row(out: &mut dyn Write, line: &str, index: Option<usize>, config: &Config) -> io::Result<()> {
+    match row_gutter(index, config) {
+        Some(gutter) if config.no_color => write!(out, "{} {}", gutter, line),
+        Some(gutter) => write!(out, "{} {}", config.theme.gutter(&gutter), line),
+        None => write!(out, "{}", line),
+    }
+}
+
+/// Whether the rows fit on a terminal of `size` without scrolling, counting
+/// lines that wrap. The last line is left for the shell prompt.
+fn fits_on_screen(results: &[String], indexes: &[Option<usize>], config: &Config, size: (u16, u16)) -> bool {
+    let (width, height) = ((size.0 as usize).max(1), size.1 as usize);
+    let mut lines = 0;
+    for (line, index) in results.iter().zip(indexes) {
+        let text = strip_ansi(line).map_or(Cow::Borrowed(line.as_str()), |(plain, _)| Cow::Owned(plain));
+        let gutter = row_gutter(*index, config).map_or(0, |gutter| gutter.len() + 1);
+        lines += (text.chars().count() + gutter).div_ceil(width).max(1);
+        if lines >= height {
+            return false;
+        }
+    }
+    true
+}
+
+/// Prints rows meant for the pager straight to `out`, for results too short
+/// to need paging or output that isn't a terminal.
+fn print_rows(out: &mut dyn Write, results: &[String], indexes: &[Option<usize>], config: &Config) -> io::Result<()> {
+    for (line, index) in results.iter().zip(indexes) {
+        write_row(out, line, *index, config)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn render_page<W: Write>(
+    screen: &mut W,
+    results: &[String],
+    indexes: &[Option<usize>],
+    tags: &[Option<u8>],
+    config: &Config,
+    view: &Viewport,
 ) -> Result<(), Box<dyn Error>> {
+    let Viewport { offset, cursor, page_height, width } = *view;
+    let total_lines = results.len();
+
     // Clear screen and hide cursor
     write!(screen, "{}{}", clear::All, cursor::Hide)?;
     
     // Draw header
     write!(
         screen,
-        "{}↑/↓: Scroll | Space: Page Down | Home/End: Jump | ESC/Ctrl+C: Exit",
+        "{}↑/↓: Move | Space: Page Down | Home/End: Jump | 1-3: Tag | 0: Untag | ESC/Ctrl+C: Exit",
         cursor::Goto(1, 1)
     )?;
     
@@ -265,13 +1418,11 @@ fn render_page<W: Write>(
         let index = indexes[content_idx];
         
         write!(screen, "{}", cursor::Goto(1, display_idx as u16 + 3))?;
-        
-        if config.line_number {
-            let formatted_line = format!("| {:>3} |", index + 1);
-            write!(screen, "{} {}", formatted_line.black(), line)?;
-        } else {
-            write!(screen, "{}", line)?;
-        }
+        // The cursor and the row's tag, if it has one
+        let pointer = if content_idx == cursor { '>' } else { ' ' };
+        let tag = tags[content_idx].map_or(' ', |tag| (b'0' + tag) as char);
+        write!(screen, "{pointer}{tag} ")?;
+        write_row(screen, line, index, config)?;
     }
     
     // Draw footer with pagination info
@@ -281,7 +1432,7 @@ fn render_page<W: Write>(
         "{}Page: {}/{} | Showing lines {}-{} of {}",
         cursor::Goto(1, footer_pos),
         offset / page_height + 1,
-        (((total_lines + page_height - 1) / page_height) - 1).max(1) ,
+        (total_lines.div_ceil(page_height) - 1).max(1) ,
         offset + 1,
         (offset + page_height).min(total_lines),
         total_lines
@@ -312,7 +1463,8 @@ mod tests {
             ignore_case,
             no_color,
             line_number,
-            stats
+            stats,
+            ..Default::default()
         }
     }
 
@@ -374,6 +1526,7 @@ contain your word.";
     fn highlight_enabled() {
         let config = create_config("fast", false, false, false, false);
         let contents = "safe, fast, productive.";
+        colored::control::set_override(true); // tests don't run on a tty
 
         let (results, _,_,_) = search(contents, &config);
 
@@ -390,4 +1543,531 @@ contain your word.";
         assert_eq!(indexes, vec![0]);
         assert_eq!(results[0], "safe, fast, productive.");
     }
+
+    #[test]
+    fn no_alt_screen_flag() {
+        let args: Vec<String> = ["minigrep", "fast", "poem.txt", "--no-alt-screen"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        let config = Config::build(&args).unwrap();
+        assert!(config.no_alt_screen);
+    }
+
+    #[test]
+    fn matches_through_ansi_sequences() {
+        let config = create_config("fast", false, true, false, false);
+        let contents = "safe, \x1b[32mfa\x1b[0mst, productive.";
+
+        let (results, indexes,_,_) = search(contents, &config);
+
+        assert_eq!(indexes, vec![0]);
+        assert_eq!(results[0], "safe, fast, productive.");
+    }
+
+    #[test]
+    fn preserves_input_colors() {
+        colored::control::set_override(true);
+        let config = create_config("productive", false, false, false, false);
+        let contents = "\x1b[32msafe\x1b[0m, fast, productive.";
+
+        let (results, _,_,_) = search(contents, &config);
+        assert!(results[0].starts_with("\x1b[32msafe\x1b[0m, fast, "));
+
+        let config = Config { strip_ansi: true, ..config };
+        let (results, _,_,_) = search(contents, &config);
+        assert!(results[0].starts_with("safe, fast, "));
+    }
+
+    #[test]
+    fn ignore_case_expanding_characters() {
+        colored::control::set_override(true);
+        // 'İ' lowercases to 'i' and a combining dot, three bytes from two
+        let config = create_config("i", true, false, false, false);
+        let contents = "\x1b[31mİx\x1b[0m line\nab\x1b[1mİ\x1b[0m\nxİ";
+
+        let (results, indexes, _, _) = search(contents, &config);
+        assert_eq!(indexes, vec![0, 1, 2]);
+        let (dotted, plain) = (config.paint_match("İ"), config.paint_match("i"));
+        assert_eq!(results[0], format!("\x1b[31m{dotted}x\x1b[0m l{plain}ne"));
+        assert_eq!(results[1], format!("ab\x1b[1m{dotted}\x1b[0m"));
+        assert_eq!(find_matches(contents, &config).0[2].spans, vec![(1, 3)]);
+    }
+
+    #[test]
+    fn strip_ansi_offsets() {
+        let (text, offsets) = strip_ansi("a\x1b[1;31mb\x1b]0;title\x07c").unwrap();
+
+        assert_eq!(text, "abc");
+        assert_eq!(offsets, vec![0, 8, 19]);
+        assert!(strip_ansi("plain").is_none());
+
+        let (text, offsets) = strip_ansi("a\x1bé b").unwrap();
+        assert_eq!(text, "a b");
+        assert_eq!(offsets, vec![0, 4, 5]);
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn group_by_capture() {
+        let config = Config::build(&args(&["minigrep", r"req=(\w+)", "log.txt", "--regex", "--group-by=1"])).unwrap();
+        let contents = "\
+GET req=a1 ok
+GET req=b2 ok
+noise
+POST req=a1 failed";
+
+        let (matches, _) = find_matches(contents, &config);
+        let groups = group_matches(matches, &config);
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].0.as_deref(), Some("a1"));
+        assert_eq!(groups[0].1.iter().map(|m| m.index).collect::<Vec<_>>(), vec![0, 3]);
+        assert_eq!(groups[1].0.as_deref(), Some("b2"));
+    }
+
+    #[test]
+    fn group_by_needs_regex_group() {
+        assert!(Config::build(&args(&["minigrep", "req", "log.txt", "--group-by=1"])).is_err());
+        assert!(Config::build(&args(&["minigrep", "req=(a)", "log.txt", "--regex", "--group-by=2"])).is_err());
+        assert!(Config::build(&args(&["minigrep", "req=(a)", "log.txt", "--regex", "--group-by"])).is_err());
+    }
+
+    #[test]
+    fn top_values_by_frequency() {
+        let config = Config::build(&args(&["minigrep", "[a-z]+@", "log.txt", "--regex", "--top=2"])).unwrap();
+        let contents = "\
+bob@ alice@
+carol@ alice@
+bob@ alice@";
+
+        let (matches, _) = find_matches(contents, &config);
+        let (top, total) = top_values(&matches, &config, 2);
+
+        assert_eq!(top, vec![("alice@".to_string(), 3), ("bob@".to_string(), 2)]);
+        assert_eq!(total, 6);
+    }
+
+    #[test]
+    fn map_keeps_selected_part() {
+        let config = Config::build(&args(&["minigrep", "GET", "access.log", "--map=field:2"])).unwrap();
+        let contents = "\
+GET /index.html 200
+POST /login 302
+GET /api/users 500";
+
+        let (matches, _) = find_matches(contents, &config);
+        let mapped = map_matches(matches, &config);
+
+        let texts: Vec<&str> = mapped.iter().map(|m| m.text.as_str()).collect();
+        assert_eq!(texts, vec!["/index.html", "/api/users"]);
+        assert_eq!(mapped[1].index, 2);
+        assert!(mapped[0].spans.is_empty());
+    }
+
+    #[test]
+    fn max_count_stops_scanning() {
+        let config = Config { max_count: Some(1), ..create_config("o", false, true, false, false) };
+        let contents = "\
+one
+two
+three";
+
+        let (matches, scanned_lines) = find_matches(contents, &config);
+
+        assert_eq!(matches.len(), 1);
+        assert_eq!(scanned_lines, 1);
+    }
+
+    #[test]
+    fn first_match_reads_only_up_to_hit() {
+        let path = env::temp_dir().join("minigrep_first_match.txt");
+        fs::write(&path, "alpha\nbeta\ngamma\ndelta\n").unwrap();
+        let config = Config {
+            file_path: path.to_string_lossy().into_owned(),
+            ..create_config("beta", false, true, false, false)
+        };
+
+        let first = find_first_match(&config, &CancellationToken::new()).unwrap();
+
+        assert_eq!(first.index, Some(1));
+        assert_eq!(first.scanned_lines, 2);
+        assert_eq!(first.bytes_read, 11);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncation_keeps_match_in_view() {
+        let config = create_config("needle", false, true, false, false);
+        let contents = format!("{}needle{}", "a".repeat(100), "b".repeat(100));
+
+        let (matches, _) = find_matches(&contents, &config);
+        let cut = truncate_match(&matches[0], 20);
+
+        assert_eq!(cut.text.chars().count(), 20);
+        assert!(cut.text.starts_with('…') && cut.text.ends_with('…'));
+        let (start, end) = cut.spans[0];
+        assert_eq!(&cut.text[start..end], "needle");
+    }
+
+    #[test]
+    fn truncation_at_line_start() {
+        let config = create_config("safe", false, true, false, false);
+        let (matches, _) = find_matches("safe, fast, productive.", &config);
+
+        let cut = truncate_match(&matches[0], 10);
+        assert_eq!(cut.text, "safe, fas…");
+        assert_eq!(cut.spans, vec![(0, 4)]);
+
+        let (matches, _) = find_matches("safe, fast, productive.", &Config { query: "tive".to_string(), ..config });
+        let cut = truncate_match(&matches[0], 10);
+        assert_eq!(cut.text, "…oductive.");
+        assert_eq!(cut.spans, vec![(7, 11)]);
+
+        let whole = truncate_match(&matches[0], 80);
+        assert_eq!(whole.text, "safe, fast, productive.");
+
+        let (matches, _) = find_matches("needlexxxxx", &create_config("needle", false, true, false, false));
+        let cut = truncate_match(&matches[0], 4);
+        assert_eq!(cut.text, "nee…");
+        assert_eq!(cut.spans, vec![(0, 3)]);
+    }
+
+    #[test]
+    fn truncation_of_wide_matches() {
+        let config = create_config("QQQQQ", false, true, false, false);
+        let (matches, _) = find_matches("QQQQQ", &config);
+        let cut = truncate_match(&matches[0], 3);
+        assert_eq!(cut.text, "QQ…");
+        assert_eq!(cut.spans, vec![(0, 2)]);
+
+        let (matches, _) = find_matches("xneedle", &create_config("needle", false, true, false, false));
+        assert_eq!(truncate_match(&matches[0], 5).text, "…nee…");
+
+        // Every window fits and starts its first match inside it
+        for line in ["ab", "xneedle", "needlex", "aaaaneedlebbbbbbb", "ééneedleé"] {
+            let (matches, _) = find_matches(line, &create_config("needle", false, true, false, false));
+            let Some(m) = matches.first() else { continue };
+            for width in 1..=line.chars().count() + 1 {
+                let cut = truncate_match(m, width);
+                assert!(cut.text.chars().count() <= width, "{line:?} at {width}: {:?}", cut.text);
+                let (start, _) = cut.spans[0];
+                assert!(cut.text[start..].starts_with('n'), "{line:?} at {width}: {:?}", cut.text);
+            }
+        }
+    }
+
+    #[test]
+    fn format_selection() {
+        let config = Config::build(&args(&["minigrep", "q", "f.txt", "--format-name=vimgrep"])).unwrap();
+        assert_eq!(config.format.unwrap().name, "vimgrep");
+
+        let config = Config::build(&args(&["minigrep", "q", "f.txt", "--json"])).unwrap();
+        assert_eq!(config.format.unwrap().name, "json");
+
+        assert!(Config::build(&args(&["minigrep", "q", "f.txt", "--json", "--csv"])).is_err());
+        assert!(Config::build(&args(&["minigrep", "q", "f.txt", "--format-name=xml"])).is_err());
+
+        assert!(Config::build(&args(&["minigrep", "q", "f.txt", "--csv", "--hash"])).unwrap().hash);
+        assert!(Config::build(&args(&["minigrep", "q", "f.txt", "--hash"])).is_err());
+        assert!(Config::build(&args(&["minigrep", "q", "f.txt", "--format-name=html", "--hash"])).is_err());
+    }
+
+    #[test]
+    fn run_with_cancel_reports_progress_and_stops() {
+        let path = env::temp_dir().join("minigrep_run_with_cancel.txt");
+        fs::write(&path, "needle\n".repeat(PROGRESS_INTERVAL * 2)).unwrap();
+        let config = Config {
+            file_path: path.to_string_lossy().into_owned(),
+            ..create_config("needle", false, true, false, false)
+        };
+
+        let mut reports = Vec::new();
+        let results = run_with_cancel(&config, &CancellationToken::new(), |p| reports.push(*p)).unwrap();
+        assert_eq!(results.matches.len(), PROGRESS_INTERVAL * 2);
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports.last().unwrap().bytes_read, reports.last().unwrap().total_bytes);
+
+        let token = CancellationToken::new();
+        let err = run_with_cancel(&config, &token, |p| {
+            if p.scanned_lines >= PROGRESS_INTERVAL {
+                token.cancel();
+            }
+        })
+        .unwrap_err();
+        assert!(err.downcast_ref::<Cancelled>().is_some());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn max_memory_sizes() {
+        assert_eq!(parse_size("max-memory", "4096"), Ok(4096));
+        assert_eq!(parse_size("max-memory", "512k"), Ok(512 * 1024));
+        assert_eq!(parse_size("max-memory", "64MB"), Ok(64 << 20));
+        assert_eq!(parse_size("max-memory", "1G"), Ok(1 << 30));
+        assert!(parse_size("max-memory", "lots").is_err());
+        assert!(parse_size("max-memory", "M").is_err());
+        assert!(parse_size("max-memory", "512KBBB").is_err());
+        assert!(parse_size("max-memory", "64MBB").is_err());
+        assert!(parse_size("max-memory", "0").is_err());
+        assert!(parse_size("max-memory", "0K").is_err());
+    }
+
+    #[test]
+    fn check_reports_without_searching() {
+        let path = env::temp_dir().join("minigrep_check.txt");
+        fs::write(&path, "alpha\nbeta\n").unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let checked = check_args(&args(&["minigrep", "check", "(a)l", &path, "--regex", "--json", "--max-count=3"])).unwrap();
+        let config = Config::build(&checked).unwrap();
+        let report = check(&config).unwrap();
+        assert!(report.contains("regex with 1 capture group(s)"));
+        assert!(report.contains("(11 bytes)"));
+        assert!(report.contains("Output: json format"));
+        assert!(report.contains("Max count: 3 lines"));
+
+        fs::remove_file(&path).unwrap();
+        assert!(check(&config).unwrap_err().to_string().contains("Can't read"));
+
+        assert!(check_args(&args(&["minigrep", "q", "f.txt", "--check"])).is_some());
+        assert!(check_args(&args(&["minigrep", "--", "check", "f.txt"])).is_none());
+        assert!(check_args(&args(&["minigrep", "q", "f.txt", "--", "--check"])).is_none());
+        assert!(check_args(&args(&["minigrep", "q", "check"])).is_none());
+    }
+
+    #[test]
+    fn ansi_debug_markers() {
+        colored::control::set_override(true);
+        let config = Config::build(&args(&["minigrep", "fast", "poem.txt", "--render=ansi-debug"])).unwrap();
+        let contents = "\x1b[32msafe\x1b[0m, fast, faster.";
+
+        let (results, _, _, _) = search(contents, &config);
+        assert_eq!(results[0], "safe, <<fast>>, <<fast>>er.");
+        assert!(Config::build(&args(&["minigrep", "fast", "poem.txt", "--render=sixel"])).is_err());
+    }
+
+    #[test]
+    fn suggestion_hint() {
+        let contents = b"receive it, receive all, relieve";
+        let config = create_config("recieve", false, true, false, false);
+        assert_eq!(
+            suggestion(&contents[..], &config).unwrap().as_deref(),
+            Some("No results for 'recieve'. Did you mean 'receive' (2 hits), 'relieve' (1 hit)?")
+        );
+
+        let regex = Config { regex: Some(Regex::new("recieve", false).unwrap()), ..create_config("recieve", false, true, false, false) };
+        assert_eq!(suggestion(&contents[..], &regex).unwrap(), None);
+    }
+
+    #[test]
+    fn rank_orders_by_relevance() {
+        let config = Config { rank: true, ..create_config("log", false, true, false, false) };
+        let contents = "catalogue\nblogs\nlog in\nlogs and more logs\nthe log of a log";
+
+        let (matches, _) = find_matches(contents, &config);
+        let ranked: Vec<usize> = rank_matches(matches, &config).iter().map(|m| m.index).collect();
+        assert_eq!(ranked, vec![4, 2, 3, 1, 0]);
+    }
+
+    #[test]
+    fn short_results_skip_the_pager() {
+        let config = create_config("a", false, true, true, false);
+        let rows = vec!["\x1b[31ma\x1b[0m".repeat(10), "a".to_string()];
+        let indexes = vec![Some(0), Some(1)];
+
+        // 10 wide text and an 8 wide gutter wrap to 2 lines on 10 columns
+        assert!(fits_on_screen(&rows, &indexes, &config, (10, 4)));
+        assert!(!fits_on_screen(&rows, &indexes, &config, (10, 3)));
+        assert!(fits_on_screen(&rows, &indexes, &config, (80, 3)));
+
+        let mut out = Vec::new();
+        print_rows(&mut out, &rows[1..], &indexes[1..], &config).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "|   2 | a\n");
+    }
+
+    #[test]
+    fn query_from_stdin_or_prompt() {
+        let piped = |input| {
+            assert_eq!(input, QueryInput::Stdin);
+            Ok("--looks-like-a-flag".to_string())
+        };
+        let config = Config::build_with(&args(&["minigrep", "--query-from-stdin", "poem.txt", "--stats"]), piped).unwrap();
+        assert_eq!(config.query, "--looks-like-a-flag");
+        assert_eq!(config.file_path, "poem.txt");
+        assert!(config.stats);
+
+        let prompted = Config::build_with(&args(&["minigrep", "poem.txt"]), |input| {
+            assert_eq!(input, QueryInput::Prompt);
+            Ok("fast".to_string())
+        });
+        assert_eq!(prompted.unwrap().query, "fast");
+
+        // Only the binary reads stdin
+        assert_eq!(Config::build(&args(&["minigrep", "poem.txt"])).err().as_deref(), Some("Not enough arguments!"));
+        assert!(Config::build(&args(&["minigrep", "--query-from-stdin", "poem.txt"])).is_err());
+
+        let no_tty = |_| Err("Not enough arguments!".to_string());
+        assert!(Config::build_with(&args(&["minigrep"]), no_tty).is_err());
+        assert!(Config::build_with(&args(&["minigrep", "a", "b", "--query-from-stdin"]), no_tty).is_err());
+        assert!(Config::build_with(&args(&["minigrep", "a", "b", "c"]), no_tty).is_err());
+    }
+
+    #[test]
+    fn dash_leading_patterns_and_paths() {
+        let no_prompt = |_| Err("Not enough arguments!".to_string());
+
+        let config = Config::build_with(&args(&["minigrep", "--stats", "--", "--help", "--stats"]), no_prompt).unwrap();
+        assert_eq!(config.query, "--help");
+        assert_eq!(config.file_path, "--stats");
+        assert!(config.stats);
+
+        let config = Config::build_with(&args(&["minigrep", "-x", "--", "-"]), no_prompt).unwrap();
+        assert_eq!((config.query.as_str(), config.file_path.as_str()), ("-x", "-"));
+
+        // Only the first `--` ends the flags, a second one is the query
+        let config = Config::build_with(&args(&["minigrep", "--", "--", "notes.txt"]), no_prompt).unwrap();
+        assert_eq!(config.query, "--");
+
+        assert!(Config::build_with(&args(&["minigrep", "--", "--stats"]), no_prompt).is_err());
+        assert!(Config::build_with(&args(&["minigrep", "q", "--", "a", "b"]), no_prompt).is_err());
+        assert!(Config::build_with(&args(&["minigrep", "--nope", "file.txt"]), no_prompt).is_err());
+    }
+
+    #[test]
+    fn invisibles_are_shown() {
+        let config = Config { show_invisibles: true, ..create_config("b", false, true, false, false) };
+        let contents = "a\tb c  \r\n";
+
+        let results = search::search_str(contents, &config.matcher(), &config.search_options());
+        assert_eq!(highlight(&results.matches[0], &config), "a→b c··␍");
+
+        // Without the flag the `\r` goes with the line ending
+        let config = create_config("b", false, true, false, false);
+        let results = search::search_str(contents, &config.matcher(), &config.search_options());
+        assert_eq!(highlight(&results.matches[0], &config), "a\tb c  ");
+    }
+
+    #[test]
+    fn files_and_buffers_end_lines_alike() {
+        let path = env::temp_dir().join(format!("minigrep_line_ends_{}.txt", std::process::id()));
+        let contents = "a\r\r\nb\r\nc\r";
+        fs::write(&path, contents).unwrap();
+
+        for keep_cr in [false, true] {
+            let options = SearchOptions { keep_cr, ..SearchOptions::default() };
+            let matcher = Matcher::literal("", false);
+            let (scanned, _) = scan_file(&path, &matcher, &options, &CancellationToken::new(), &mut |_| {}).unwrap();
+            let buffered = search::search_str(contents, &matcher, &options);
+            let texts = |results: &SearchResults| results.matches.iter().map(|m| m.text.clone()).collect::<Vec<_>>();
+            assert_eq!(texts(&scanned), texts(&buffered));
+            assert_eq!(texts(&scanned).len(), 3);
+        }
+        assert_eq!(search::trim_line_end("a\r\r\n", false), "a\r");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn long_lines_are_cut_while_reading() {
+        let mut reader = io::Cursor::new("short\nabcdefgh\nxyz\nné\n".as_bytes());
+        let mut buf = Vec::new();
+        let mut lines = Vec::new();
+        loop {
+            buf.clear();
+            match read_line_capped(&mut reader, &mut buf, 3).unwrap() {
+                (0, _) => break,
+                (_, cut) => lines.push((String::from_utf8(buf.clone()).unwrap(), cut)),
+            }
+        }
+
+        let expected = [("sho", true), ("abc", true), ("xyz", false), ("né", false)];
+        assert_eq!(lines, expected.map(|(line, cut)| (line.to_string(), cut)));
+
+        let options = SearchOptions { max_line_length: Some(2), ..Default::default() };
+        let results = search::search_str("né\nab", &Matcher::literal("n", false), &options);
+        assert_eq!((results.matches[0].text.as_str(), results.long_lines), ("n", 1));
+    }
+
+    #[test]
+    fn then_stages_narrow_results() {
+        let argv = args(&["minigrep", "error", "log.txt", "--line-number", "--then", "--invert-match", "debug", "--then", "--regex", "ret(ry)?"]);
+        let config = Config::build(&argv).unwrap();
+        assert_eq!(config.stages.len(), 2);
+        assert!(config.line_number);
+
+        let contents = "error: retry\nerror: retry (debug)\nerror: gave up\ninfo: retry";
+        let (matches, _) = find_matches(contents, &config);
+        assert_eq!(matches.iter().map(|m| m.index).collect::<Vec<_>>(), vec![0]);
+        assert_eq!(matches[0].spans, vec![(0, 5), (7, 12)]);
+
+        // Flags other than the stage's own still apply to the whole search
+        let config = Config::build(&args(&["minigrep", "a", "b", "--then", "--stats", "--", "--d"])).unwrap();
+        assert!(config.stats);
+        assert!(config.matcher().spans("a c").is_none());
+        assert!(config.matcher().spans("a --d").is_some());
+
+        assert!(Config::build(&args(&["minigrep", "a", "b", "--then"])).is_err());
+        assert!(Config::build(&args(&["minigrep", "a", "b", "--then", "c", "d"])).is_err());
+    }
+
+    #[test]
+    fn root_resolves_file_paths() {
+        let base = env::temp_dir().join("minigrep_root_paths");
+        fs::create_dir_all(base.join("repo/.git")).unwrap();
+        fs::create_dir_all(base.join("repo/src/deep")).unwrap();
+
+        let config = Config::build(&args(&["minigrep", "q", "src/lib.rs", "--root=auto"])).unwrap();
+        assert_eq!(config.path_in(&base.join("repo/src/deep")), Ok(base.join("repo/src/lib.rs")));
+        // Displayed paths stay relative to the root
+        assert_eq!(config.file_path, "src/lib.rs");
+
+        let config = Config::build(&args(&["minigrep", "q", "lib.rs", "--root=../src"])).unwrap();
+        assert_eq!(config.path_in(&base.join("repo/docs")), Ok(base.join("repo/docs/../src/lib.rs")));
+
+        let config = Config::build(&args(&["minigrep", "q", "lib.rs"])).unwrap();
+        assert_eq!(config.path_in(&base), Ok(base.join("lib.rs")));
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn cursor_stays_on_the_page() {
+        let mut view = Viewport { offset: 0, cursor: 5, page_height: 3, width: 80 };
+        view.follow_cursor();
+        assert_eq!(view.offset, 3);
+
+        view.cursor = 1;
+        view.follow_cursor();
+        assert_eq!(view.offset, 1);
+    }
+
+    #[test]
+    fn tagged_lines_are_exported() {
+        let config = create_config("err", false, true, false, false);
+        let texts = vec![None, Some("err one".to_string()), Some("err two".to_string()), Some("err three".to_string())];
+        let indexes = vec![None, Some(0), Some(4), Some(9)];
+        let tags = vec![None, Some(2), None, Some(2)];
+        let base = env::temp_dir().join("minigrep_export_test");
+        let base = base.to_string_lossy();
+
+        let written = export_tags(&base, &indexes, &texts, &tags, &config).unwrap();
+        assert_eq!(written, vec![format!("{base}.2")]);
+        assert_eq!(fs::read_to_string(&written[0]).unwrap(), "1:err one\n10:err three\n");
+        fs::remove_file(&written[0]).unwrap();
+
+        let report = format!("{base}.json");
+        export_tags(&report, &indexes, &texts, &tags, &config).unwrap();
+        let value = json::parse(fs::read_to_string(&report).unwrap().trim()).unwrap();
+        let set = value.get("tags").and_then(|tags| tags.get("2")).and_then(Value::as_array).unwrap();
+        assert_eq!(set[1].get("text").and_then(Value::as_str), Some("err three"));
+        assert_eq!(value.get("query").and_then(Value::as_str), Some("err"));
+        fs::remove_file(report).unwrap();
+
+        // Nothing is paged, so nothing could be tagged
+        assert!(Config::build(&args(&["minigrep", "err", "f.txt", "--export=out", "--json"])).is_err());
+        assert!(Config::build(&args(&["minigrep", "err", "f.txt", "--export=out", "--quiet"])).is_err());
+    }
 }
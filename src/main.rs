@@ -1,23 +1,100 @@
 use std::process;
 use std::env;
+#[cfg(unix)]
+use std::path::PathBuf;
 
-use minigrep::run;
-use minigrep::Config;
+use minigrep::cancel;
+use minigrep::cli;
+#[cfg(unix)]
+use minigrep::daemon;
+use minigrep::{check, check_args, read_query, run};
+use minigrep::{CancellationToken, Cancelled, Config};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let config = Config::build(&args).unwrap_or_else(|err| {
+    // Nothing after `--` is a flag, so `minigrep -- --help notes.txt` searches for "--help"
+    let options = &args[..args.iter().position(|arg| arg == "--").unwrap_or(args.len())];
+
+    if options.iter().any(|arg| arg == "--help") {
+        print!("{}", cli::help());
+        return;
+    }
+    if let Some(shell) = options.iter().find_map(|arg| arg.strip_prefix("--completions=")) {
+        match cli::completions(shell) {
+            Ok(script) => print!("{script}"),
+            Err(err) => {
+                eprintln!("{err}");
+                process::exit(1)
+            }
+        }
+        return;
+    }
+
+    #[cfg(unix)]
+    if let Some(serve) = options.get(1).filter(|arg| *arg == "--serve" || arg.starts_with("--serve=")) {
+        let socket = match serve.strip_prefix("--serve=") {
+            Some(socket) => PathBuf::from(socket),
+            None => args.get(2).map_or_else(daemon::default_socket, PathBuf::from),
+        };
+        if let Err(e) = daemon::serve(&socket) {
+            eprintln!("Application error: {e}");
+            process::exit(1)
+        }
+        return;
+    }
+    #[cfg(unix)]
+    if let Some(position) = options.iter().position(|arg| arg == "--client" || arg.starts_with("--client=")) {
+        let socket = args[position].strip_prefix("--client=").map_or_else(daemon::default_socket, PathBuf::from);
+        let forwarded: Vec<String> = args[1..].iter().enumerate().filter(|(i, _)| i + 1 != position).map(|(_, arg)| arg.clone()).collect();
+        match daemon::client(&socket, &forwarded) {
+            Ok(status) => process::exit(status),
+            Err(e) => {
+                eprintln!("Application error: {e}");
+                process::exit(if forwarded.iter().any(|arg| arg == "--quiet") { 2 } else { 1 })
+            }
+        }
+    }
+
+    // `minigrep check QUERY FILE [FLAGS]` validates an invocation without searching
+    if let Some(args) = check_args(&args) {
+        let report = Config::build(&args).map_err(Into::into).and_then(|config| check(&config));
+        match report {
+            Ok(report) => print!("{report}"),
+            Err(e) => {
+                eprintln!("Check failed: {e}");
+                process::exit(1)
+            }
+        }
+        return;
+    }
+
+    // Only a real search asks for a missing query
+    let config = Config::build_with(&args, read_query).unwrap_or_else(|err| {
         eprintln!("Problem parsing argument: {err}");
-        process::exit(1)
+        process::exit(if options.iter().any(|arg| arg == "--quiet") { 2 } else { 1 })
     });
 
 
     
     
-    if let Err(e) = run(config){
-        eprintln!("Application error: {e}");
-        process::exit(1)
+    // Ctrl+C stops the search through the same token a library caller would use
+    let token = CancellationToken::new();
+    cancel::cancel_on_interrupt(&token);
 
+    let quiet = config.quiet;
+    match run(config, &token) {
+        // Scripts check `--quiet` runs through the exit status alone
+        Ok(false) if quiet => process::exit(1),
+        Ok(_) => {}
+        Err(e) if e.is::<Cancelled>() => {
+            eprintln!("Search interrupted");
+            process::exit(130)
+        }
+        Err(e) => {
+            eprintln!("Application error: {e}");
+            // 1 already means "nothing matched" to `--quiet` callers
+            process::exit(if quiet { 2 } else { 1 })
+        }
     }
 
 }
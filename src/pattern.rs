@@ -0,0 +1,631 @@
+//! A small regular expression engine.
+//!
+//! Supports literals, `.`, character classes (`[a-z]`, `[^0-9]`), the `\d \w \s`
+//! shorthands and their negations, anchors (`^ $ \b`), capturing and
+//! non-capturing groups, alternation and the `* + ? {n} {n,} {n,m}` quantifiers
+//! (lazy with a trailing `?`).
+//!
+//! Patterns compile to a list of instructions that run over the text as a set
+//! of threads, one per place in the pattern, advanced a character at a time.
+//! There is no recursion and no backtracking, so matching takes time linear in
+//! the length of the line whatever the pattern.
+
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    Any,
+    Class { ranges: Vec<(char, char)>, negated: bool },
+    Start,
+    End,
+    WordBoundary,
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat { node: Box<Node>, min: usize, max: Option<usize>, greedy: bool },
+}
+
+/// Byte ranges of a match and its capture groups; index 0 is the whole match.
+pub type Captures = Vec<Option<(usize, usize)>>;
+
+#[derive(Debug, Clone)]
+pub struct Regex {
+    program: Vec<Inst>,
+    groups: usize,
+    ignore_case: bool,
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+    pattern: &'a str,
+}
+
+impl Parser<'_> {
+    fn error(&self, msg: &str) -> String {
+        format!("Invalid regex '{}': {} at position {}", self.pattern, msg, self.pos)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concat()?];
+        while self.eat('|') {
+            branches.push(self.concat()?);
+        }
+        Ok(if branches.len() == 1 { branches.pop().unwrap() } else { Node::Alt(branches) })
+    }
+
+    fn concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantifier(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let c = self.peek().unwrap();
+        self.pos += 1;
+        Ok(match c {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '(' => {
+                let index = if self.eat('?') {
+                    if !self.eat(':') {
+                        return Err(self.error("unsupported group syntax"));
+                    }
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+                let inner = self.alternation()?;
+                if !self.eat(')') {
+                    return Err(self.error("unclosed group"));
+                }
+                Node::Group(Box::new(inner), index)
+            }
+            '[' => self.class()?,
+            '\\' => self.escape()?,
+            '*' | '+' | '?' => return Err(self.error("nothing to repeat")),
+            c => Node::Char(c),
+        })
+    }
+
+    fn escape(&mut self) -> Result<Node, String> {
+        let c = self.peek().ok_or_else(|| self.error("trailing backslash"))?;
+        self.pos += 1;
+        Ok(match c {
+            'b' => Node::WordBoundary,
+            'd' | 'w' | 's' | 'D' | 'W' | 'S' => Node::Class {
+                ranges: shorthand(c.to_ascii_lowercase()),
+                negated: c.is_ascii_uppercase(),
+            },
+            'n' => Node::Char('\n'),
+            't' => Node::Char('\t'),
+            'r' => Node::Char('\r'),
+            c => Node::Char(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+
+        loop {
+            let c = self.peek().ok_or_else(|| self.error("unclosed character class"))?;
+            self.pos += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+
+            let start = if c == '\\' {
+                let e = self.peek().ok_or_else(|| self.error("trailing backslash"))?;
+                self.pos += 1;
+                match e {
+                    'd' | 'w' | 's' => {
+                        ranges.extend(shorthand(e));
+                        continue;
+                    }
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    e => e,
+                }
+            } else {
+                c
+            };
+
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&n| n != ']') {
+                self.pos += 1;
+                let mut end = self.peek().unwrap();
+                self.pos += 1;
+                if end == '\\' {
+                    end = self.peek().ok_or_else(|| self.error("trailing backslash"))?;
+                    self.pos += 1;
+                }
+                if end < start {
+                    return Err(self.error("invalid class range"));
+                }
+                ranges.push((start, end));
+            } else {
+                ranges.push((start, start));
+            }
+        }
+
+        Ok(Node::Class { ranges, negated })
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect::<String>().parse().ok()
+    }
+
+    fn quantifier(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                let save = self.pos;
+                self.pos += 1;
+                match self.number() {
+                    Some(min) => {
+                        let max = if self.eat(',') { self.number() } else { Some(min) };
+                        if !self.eat('}') {
+                            return Err(self.error("unclosed repetition"));
+                        }
+                        if max.is_some_and(|max| max < min) {
+                            return Err(self.error("invalid repetition range"));
+                        }
+                        self.pos -= 1;
+                        (min, max)
+                    }
+                    // A brace that doesn't start a repetition is a literal
+                    None => {
+                        self.pos = save;
+                        return Ok(atom);
+                    }
+                }
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+
+        if matches!(atom, Node::Start | Node::End | Node::WordBoundary) {
+            return Err(self.error("nothing to repeat"));
+        }
+        let greedy = !self.eat('?');
+        Ok(Node::Repeat { node: Box::new(atom), min, max, greedy })
+    }
+}
+
+fn shorthand(c: char) -> Vec<(char, char)> {
+    match c {
+        'd' => vec![('0', '9')],
+        'w' => vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')],
+        _ => vec![(' ', ' '), ('\t', '\t'), ('\n', '\n'), ('\r', '\r'), ('\x0b', '\x0c')],
+    }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Programs that expand past this many instructions, e.g. from nested `{n}`
+/// counts, are rejected rather than compiled.
+const MAX_PROGRAM: usize = 100_000;
+
+#[derive(Debug, Clone)]
+enum Inst {
+    Char(char),
+    Any,
+    Class { ranges: Vec<(char, char)>, negated: bool },
+    Start,
+    End,
+    WordBoundary,
+    /// Records the current position in a capture slot
+    Save(usize),
+    /// Tries both targets, preferring the first
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+struct Compiler<'a> {
+    program: Vec<Inst>,
+    pattern: &'a str,
+}
+
+impl Compiler<'_> {
+    fn push(&mut self, inst: Inst) -> Result<usize, String> {
+        if self.program.len() >= MAX_PROGRAM {
+            return Err(format!("Invalid regex '{}': pattern is too large", self.pattern));
+        }
+        self.program.push(inst);
+        Ok(self.program.len() - 1)
+    }
+
+    /// A split whose targets are patched once they are known
+    fn split(&mut self) -> Result<usize, String> {
+        self.push(Inst::Split(0, 0))
+    }
+
+    fn patch(&mut self, split: usize, body: usize, exit: usize, greedy: bool) {
+        self.program[split] = if greedy { Inst::Split(body, exit) } else { Inst::Split(exit, body) };
+    }
+
+    fn node(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Char(c) => self.push(Inst::Char(*c)).map(drop),
+            Node::Any => self.push(Inst::Any).map(drop),
+            Node::Class { ranges, negated } => self.push(Inst::Class { ranges: ranges.clone(), negated: *negated }).map(drop),
+            Node::Start => self.push(Inst::Start).map(drop),
+            Node::End => self.push(Inst::End).map(drop),
+            Node::WordBoundary => self.push(Inst::WordBoundary).map(drop),
+            Node::Group(inner, None) => self.node(inner),
+            Node::Group(inner, Some(i)) => {
+                self.push(Inst::Save(2 * i))?;
+                self.node(inner)?;
+                self.push(Inst::Save(2 * i + 1)).map(drop)
+            }
+            Node::Concat(nodes) => nodes.iter().try_for_each(|node| self.node(node)),
+            Node::Alt(branches) => {
+                let mut jumps = Vec::new();
+                for (i, branch) in branches.iter().enumerate() {
+                    if i + 1 == branches.len() {
+                        self.node(branch)?;
+                    } else {
+                        let split = self.split()?;
+                        self.node(branch)?;
+                        jumps.push(self.push(Inst::Jump(0))?);
+                        let next = self.program.len();
+                        self.patch(split, split + 1, next, true);
+                    }
+                }
+                let end = self.program.len();
+                for jump in jumps {
+                    self.program[jump] = Inst::Jump(end);
+                }
+                Ok(())
+            }
+            Node::Repeat { node, min, max, greedy } => {
+                for _ in 0..*min {
+                    self.node(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.split()?;
+                        self.node(node)?;
+                        self.push(Inst::Jump(split))?;
+                        let exit = self.program.len();
+                        self.patch(split, split + 1, exit, *greedy);
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.split()?);
+                            self.node(node)?;
+                        }
+                        let exit = self.program.len();
+                        for split in splits {
+                            self.patch(split, split + 1, exit, *greedy);
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The threads alive at one position, in priority order, each with its own
+/// capture slots. A program counter is only ever added once per position,
+/// which is what keeps matching linear in the length of the text.
+struct Threads {
+    pcs: Vec<usize>,
+    seen: Vec<bool>,
+    /// Every program counter marked in `seen`, including the ones that don't wait
+    visited: Vec<usize>,
+    slots: Vec<Vec<Option<usize>>>,
+}
+
+impl Threads {
+    fn new(program: usize, slots: usize) -> Threads {
+        Threads { pcs: Vec::new(), seen: vec![false; program], visited: Vec::new(), slots: vec![vec![None; slots]; program] }
+    }
+
+    fn clear(&mut self) {
+        for &pc in &self.visited {
+            self.seen[pc] = false;
+        }
+        self.visited.clear();
+        self.pcs.clear();
+    }
+}
+
+enum Frame {
+    Explore(usize),
+    Restore(usize, Option<usize>),
+}
+
+struct Matcher<'a> {
+    program: &'a [Inst],
+    chars: &'a [(usize, char)],
+    text_len: usize,
+    ignore_case: bool,
+}
+
+impl Matcher<'_> {
+    fn char_at(&self, pos: usize) -> Option<char> {
+        self.chars.get(pos).map(|&(_, c)| c)
+    }
+
+    fn byte(&self, pos: usize) -> usize {
+        self.chars.get(pos).map_or(self.text_len, |&(b, _)| b)
+    }
+
+    fn class_contains(&self, ranges: &[(char, char)], c: char) -> bool {
+        let hit = |c: char| ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+        hit(c)
+            || (self.ignore_case
+                && (c.to_lowercase().any(hit) || c.to_uppercase().any(hit)))
+    }
+
+    fn eq(&self, a: char, b: char) -> bool {
+        a == b || (self.ignore_case && a.to_lowercase().eq(b.to_lowercase()))
+    }
+
+    /// Follows every instruction that doesn't consume a character from `pc`,
+    /// adding the threads that wait on one to `list`.
+    fn add(&self, list: &mut Threads, pc: usize, pos: usize, slots: &mut [Option<usize>], stack: &mut Vec<Frame>) {
+        stack.push(Frame::Explore(pc));
+        while let Some(frame) = stack.pop() {
+            let pc = match frame {
+                Frame::Explore(pc) => pc,
+                Frame::Restore(slot, old) => {
+                    slots[slot] = old;
+                    continue;
+                }
+            };
+            if list.seen[pc] {
+                continue;
+            }
+            list.seen[pc] = true;
+            list.visited.push(pc);
+
+            match &self.program[pc] {
+                Inst::Jump(to) => stack.push(Frame::Explore(*to)),
+                Inst::Split(first, second) => {
+                    stack.push(Frame::Explore(*second));
+                    stack.push(Frame::Explore(*first));
+                }
+                Inst::Save(slot) => {
+                    stack.push(Frame::Restore(*slot, slots[*slot]));
+                    slots[*slot] = Some(self.byte(pos));
+                    stack.push(Frame::Explore(pc + 1));
+                }
+                Inst::Start => {
+                    if pos == 0 {
+                        stack.push(Frame::Explore(pc + 1));
+                    }
+                }
+                Inst::End => {
+                    if pos == self.chars.len() {
+                        stack.push(Frame::Explore(pc + 1));
+                    }
+                }
+                Inst::WordBoundary => {
+                    let before = pos > 0 && self.char_at(pos - 1).is_some_and(is_word);
+                    let after = self.char_at(pos).is_some_and(is_word);
+                    if before != after {
+                        stack.push(Frame::Explore(pc + 1));
+                    }
+                }
+                Inst::Char(_) | Inst::Any | Inst::Class { .. } | Inst::Match => {
+                    list.pcs.push(pc);
+                    list.slots[pc].copy_from_slice(slots);
+                }
+            }
+        }
+    }
+
+    fn step(&self, inst: &Inst, pos: usize) -> bool {
+        let Some(c) = self.char_at(pos) else {
+            return false;
+        };
+        match inst {
+            Inst::Char(expected) => self.eq(*expected, c),
+            Inst::Any => c != '\n',
+            Inst::Class { ranges, negated } => self.class_contains(ranges, c) != *negated,
+            _ => false,
+        }
+    }
+
+    /// Capture slots of the leftmost match starting at character `first` or later.
+    fn search(&self, first: usize, slot_count: usize) -> Option<Vec<Option<usize>>> {
+        let mut current = Threads::new(self.program.len(), slot_count);
+        let mut next = Threads::new(self.program.len(), slot_count);
+        let mut scratch = vec![None; slot_count];
+        let mut stack = Vec::new();
+        let mut matched = None;
+
+        for pos in first..=self.chars.len() {
+            // A new attempt starts here, behind every attempt already under way
+            if matched.is_none() {
+                scratch.fill(None);
+                self.add(&mut current, 0, pos, &mut scratch, &mut stack);
+            }
+            if current.pcs.is_empty() && matched.is_some() {
+                break;
+            }
+
+            for i in 0..current.pcs.len() {
+                let pc = current.pcs[i];
+                let inst = &self.program[pc];
+                if let Inst::Match = inst {
+                    // Threads after this one have lower priority
+                    matched = Some(current.slots[pc].clone());
+                    break;
+                }
+                if self.step(inst, pos) {
+                    scratch.copy_from_slice(&current.slots[pc]);
+                    self.add(&mut next, pc + 1, pos + 1, &mut scratch, &mut stack);
+                }
+            }
+
+            std::mem::swap(&mut current, &mut next);
+            next.clear();
+        }
+        matched
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str, ignore_case: bool) -> Result<Regex, String> {
+        let mut parser = Parser { chars: pattern.chars().collect(), pos: 0, groups: 0, pattern };
+        let node = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("unmatched ')'"));
+        }
+
+        let mut compiler = Compiler { program: Vec::new(), pattern };
+        compiler.push(Inst::Save(0))?;
+        compiler.node(&node)?;
+        compiler.push(Inst::Save(1))?;
+        compiler.push(Inst::Match)?;
+        Ok(Regex { program: compiler.program, groups: parser.groups, ignore_case })
+    }
+
+    /// Number of capturing groups, not counting the whole match
+    pub fn group_count(&self) -> usize {
+        self.groups
+    }
+
+    fn search(&self, text: &str, chars: &[(usize, char)], first: usize) -> Option<Captures> {
+        let matcher = Matcher { program: &self.program, chars, text_len: text.len(), ignore_case: self.ignore_case };
+        let slots = matcher.search(first, 2 * (self.groups + 1))?;
+        Some(slots.chunks(2).map(|pair| pair[0].zip(pair[1])).collect())
+    }
+
+    /// The leftmost match starting at or after byte offset `start`.
+    pub fn captures_at(&self, text: &str, start: usize) -> Option<Captures> {
+        let chars: Vec<(usize, char)> = text.char_indices().collect();
+        let first = chars.partition_point(|&(b, _)| b < start);
+        self.search(text, &chars, first)
+    }
+
+    pub fn captures(&self, text: &str) -> Option<Captures> {
+        self.captures_at(text, 0)
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.captures(text).is_some()
+    }
+
+    /// Byte ranges of every non-overlapping match, skipping empty ones.
+    pub fn find_iter(&self, text: &str) -> Vec<(usize, usize)> {
+        let chars: Vec<(usize, char)> = text.char_indices().collect();
+        let mut spans = Vec::new();
+        let mut first = 0;
+        while first <= chars.len() {
+            let Some((s, e)) = self.search(text, &chars, first).and_then(|caps| caps[0]) else {
+                break;
+            };
+            let end = chars.partition_point(|&(b, _)| b < e);
+            if e > s {
+                spans.push((s, e));
+                first = end;
+            } else {
+                // Step over the character at an empty match
+                first = end + 1;
+            }
+        }
+        spans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, text: &str) -> Vec<(usize, usize)> {
+        Regex::new(pattern, false).unwrap().find_iter(text)
+    }
+
+    #[test]
+    fn literals_classes_and_quantifiers() {
+        assert_eq!(find("fast", "safe, fast, productive."), vec![(6, 10)]);
+        assert_eq!(find("[a-c]+", "abc xyz cab"), vec![(0, 3), (8, 11)]);
+        assert_eq!(find(r"\d{2,3}", "1 22 4444"), vec![(2, 4), (5, 8)]);
+        assert_eq!(find("^a|b$", "ab"), vec![(0, 1), (1, 2)]);
+        assert_eq!(find(r"\bis\b", "this is it"), vec![(5, 7)]);
+        assert_eq!(find("a.*?b", "aXbaYb"), vec![(0, 3), (3, 6)]);
+    }
+
+    #[test]
+    fn capture_groups() {
+        let re = Regex::new(r"id=(\w+)(?: user=(\w+))?", false).unwrap();
+        assert_eq!(re.group_count(), 2);
+
+        let caps = re.captures("req id=ab12 user=bob").unwrap();
+        assert_eq!(caps[1], Some((7, 11)));
+        assert_eq!(caps[2], Some((17, 20)));
+
+        let caps = re.captures("req id=ab12").unwrap();
+        assert_eq!(caps[2], None);
+    }
+
+    #[test]
+    fn ignore_case() {
+        let re = Regex::new("r[u]st", true).unwrap();
+        assert!(re.is_match("Trust"));
+        assert!(re.is_match("RUST"));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!(Regex::new("(abc", false).is_err());
+        assert!(Regex::new("abc)", false).is_err());
+        assert!(Regex::new("*a", false).is_err());
+        assert!(Regex::new("[z-a]", false).is_err());
+        assert!(Regex::new("(a{1000}){1000}", false).is_err());
+    }
+
+    #[test]
+    fn long_lines() {
+        let line = "a".repeat(20_000);
+        assert_eq!(find("a+$", &line), vec![(0, 20_000)]);
+        assert_eq!(find(r"\ba+\b", &format!(" {line}")), vec![(1, 20_001)]);
+    }
+
+    #[test]
+    fn pathological_patterns() {
+        let line = "a".repeat(5_000);
+        assert!(find("(a*)*b", &line).is_empty());
+        assert!(find("(a|aa)+c", &line).is_empty());
+        assert_eq!(find("(a|a)*", &line), vec![(0, 5_000)]);
+    }
+}
@@ -0,0 +1,31 @@
+use std::path::{Path, PathBuf};
+
+/// Entries that mark the top of a project for `--root=auto`
+pub const MARKERS: &[&str] = &[".git", "Cargo.toml"];
+
+/// The nearest of `start` and its ancestors that holds one of `MARKERS`.
+pub fn find_root(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .find(|dir| MARKERS.iter().any(|marker| dir.join(marker).exists()))
+        .map(Path::to_path_buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[test]
+    fn nearest_marked_ancestor() {
+        let base = env::temp_dir().join("minigrep_root_test");
+        let nested = base.join("project/crates/tool/src");
+        fs::create_dir_all(&nested).unwrap();
+        fs::create_dir_all(base.join("project/.git")).unwrap();
+        fs::write(base.join("project/crates/tool/Cargo.toml"), "").unwrap();
+
+        assert_eq!(find_root(&nested), Some(base.join("project/crates/tool")));
+        assert_eq!(find_root(&base.join("project/crates")), Some(base.join("project")));
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -0,0 +1,465 @@
+//! Line matching shared by the CLI and embedders.
+//!
+//! `search_bytes` searches an in-memory buffer with no file or `Config`
+//! involved; the CLI runs the same `Matcher` over the files it reads.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+
+use strsim::damerau_levenshtein;
+
+use crate::filter::Where;
+use crate::pattern::Regex;
+use crate::read_line_capped;
+
+/// Lowercases `s` when asked to, keeping the byte offset in `s` of every byte of the result
+/// so spans found in the lowered text can be mapped back onto the original.
+pub(crate) fn conditional_lowercase(s: &str, ignore_case: bool) -> (Cow<'_, str>, Option<Vec<usize>>) {
+    if !ignore_case {
+        return (Cow::Borrowed(s), None);
+    }
+
+    let mut lowered = String::with_capacity(s.len());
+    let mut offsets = Vec::with_capacity(s.len());
+    for (pos, c) in s.char_indices() {
+        for l in c.to_lowercase() {
+            lowered.push(l);
+            offsets.resize(lowered.len(), pos);
+        }
+    }
+    (Cow::Owned(lowered), Some(offsets))
+}
+
+/// Byte ranges of every occurrence of the query in `line`.
+fn find_spans(line: &str, query: &str, ignore_case: bool) -> Vec<(usize, usize)> {
+    let (haystack, offsets) = conditional_lowercase(line, ignore_case);
+    let map = |pos: usize| match &offsets {
+        Some(offsets) => offsets.get(pos).copied().unwrap_or(line.len()),
+        None => pos,
+    };
+    // Lowercasing can turn one character into several, so a match ending
+    // inside one covers all of the original character
+    let map_end = |end: usize| match &offsets {
+        Some(offsets) if end > 0 => {
+            let last = offsets[end - 1];
+            last + line[last..].chars().next().map_or(0, char::len_utf8)
+        }
+        _ => end,
+    };
+
+    haystack
+        .match_indices(query)
+        .filter(|(_, m)| !m.is_empty())
+        .map(|(pos, m)| (map(pos), map_end(pos + m.len())))
+        .collect()
+}
+
+/// Removes ANSI escape sequences (CSI, OSC and two-byte escapes) from `s`.
+///
+/// Returns `None` when there is nothing to strip. Otherwise returns the plain text
+/// along with the byte offset in `s` of every byte of it.
+pub fn strip_ansi(s: &str) -> Option<(String, Vec<usize>)> {
+    if !s.contains('\x1b') {
+        return None;
+    }
+
+    let bytes = s.as_bytes();
+    let mut text = String::with_capacity(s.len());
+    let mut offsets = Vec::with_capacity(s.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != 0x1b {
+            let c = s[i..].chars().next().unwrap();
+            text.push(c);
+            offsets.extend(i..i + c.len_utf8());
+            i += c.len_utf8();
+            continue;
+        }
+
+        i += 1;
+        match bytes.get(i) {
+            // CSI: parameters and intermediates up to a final byte in @..~
+            Some(b'[') => {
+                i += 1;
+                while i < bytes.len() && !(0x40..=0x7e).contains(&bytes[i]) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            // OSC: terminated by BEL or ESC \
+            Some(b']') => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != 0x07 && !(bytes[i] == 0x1b && bytes.get(i + 1) == Some(&b'\\')) {
+                    i += 1;
+                }
+                i += if bytes.get(i) == Some(&0x07) { 1 } else { 2 };
+            }
+            // Two-character sequence, ending with whatever character follows
+            Some(_) => i += s[i..].chars().next().map_or(1, char::len_utf8),
+            None => {}
+        }
+    }
+
+    Some((text, offsets))
+}
+
+/// A line that matched the query.
+#[derive(Debug, Clone)]
+pub struct LineMatch {
+    /// 0-based line index in the input
+    pub index: usize,
+    /// The line with any escape sequences from the input removed
+    pub text: String,
+    /// The line as read, kept only when it contained escape sequences
+    pub raw: Option<String>,
+    /// Byte ranges of every match in `text`
+    pub spans: Vec<(usize, usize)>,
+    /// Value of the `--group-by` capture, set by `group_matches`
+    pub group: Option<String>,
+}
+
+/// What to look for in each line.
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    /// A plain substring. An empty query matches every line without highlighting anything.
+    Literal { query: String, ignore_case: bool },
+    Regex(Regex),
+    /// Every stage of a `--then` chain, each narrowing down the lines of the one before
+    Pipeline(Vec<Stage>),
+}
+
+/// One stage of a pipeline; `invert` keeps the lines that don't match instead.
+#[derive(Debug, Clone)]
+pub struct Stage {
+    pub matcher: Matcher,
+    pub invert: bool,
+}
+
+impl Matcher {
+    pub fn literal(query: &str, ignore_case: bool) -> Matcher {
+        let (query, _) = conditional_lowercase(query, ignore_case);
+        Matcher::Literal { query: query.into_owned(), ignore_case }
+    }
+
+    pub fn regex(pattern: &str, ignore_case: bool) -> Result<Matcher, String> {
+        Ok(Matcher::Regex(Regex::new(pattern, ignore_case)?))
+    }
+
+    /// Spans of every match in `text`, or `None` when the line doesn't match.
+    pub fn spans(&self, text: &str) -> Option<Vec<(usize, usize)>> {
+        let spans = match self {
+            Matcher::Literal { query, .. } if query.is_empty() => return Some(Vec::new()),
+            Matcher::Literal { query, ignore_case } => find_spans(text, query, *ignore_case),
+            Matcher::Regex(regex) => regex.find_iter(text),
+            Matcher::Pipeline(stages) => return pipeline_spans(stages, text),
+        };
+        (!spans.is_empty()).then_some(spans)
+    }
+
+    /// Matches a single line, stripping escape sequences before matching.
+    pub fn match_line(&self, index: usize, line: &str) -> Option<LineMatch> {
+        let (text, raw) = match strip_ansi(line) {
+            Some((text, _)) => (text, Some(line.to_string())),
+            None => (line.to_string(), None),
+        };
+
+        let spans = self.spans(&text)?;
+        Some(LineMatch { index, text, raw, spans, group: None })
+    }
+}
+
+/// Spans of every non-inverted stage, merged where they overlap so each part of
+/// the line is highlighted once. Inverted stages only decide whether it matches.
+fn pipeline_spans(stages: &[Stage], text: &str) -> Option<Vec<(usize, usize)>> {
+    let mut spans = Vec::new();
+    for stage in stages {
+        match (stage.matcher.spans(text), stage.invert) {
+            (Some(found), false) => spans.extend(found),
+            (None, true) => {}
+            _ => return None,
+        }
+    }
+
+    spans.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(spans.len());
+    for (start, end) in spans {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    Some(merged)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    /// Stop after this many matching lines
+    pub max_count: Option<usize>,
+    /// Approximate bytes the kept matches may take; matching lines past it are
+    /// counted but dropped
+    pub max_memory: Option<usize>,
+    /// Only matching lines that pass this comparison count as matches
+    pub filter: Option<Where>,
+    /// Keep the `\r` of `\r\n` line endings as part of the line
+    pub keep_cr: bool,
+    /// Only search the first this many bytes of each line
+    pub max_line_length: Option<usize>,
+}
+
+impl SearchOptions {
+    /// Bytes of each line that are searched: `max_line_length`, and never more
+    /// than `max_memory`, which a single line mustn't exceed either.
+    pub fn line_limit(&self) -> usize {
+        self.max_line_length.into_iter().chain(self.max_memory).min().unwrap_or(usize::MAX)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchResults {
+    pub matches: Vec<LineMatch>,
+    pub scanned_lines: usize,
+    /// Matching lines left out of `matches` to stay under `max_memory`
+    pub dropped_lines: usize,
+    /// Lines cut to the `line_limit`, matching or not
+    pub long_lines: usize,
+}
+
+impl SearchResults {
+    /// Every matching line seen, kept or not
+    pub fn matched_lines(&self) -> usize {
+        self.matches.len() + self.dropped_lines
+    }
+}
+
+impl LineMatch {
+    /// Rough heap and inline size, for `max_memory` accounting
+    pub fn approx_size(&self) -> usize {
+        std::mem::size_of::<LineMatch>()
+            + self.text.len()
+            + self.raw.as_ref().map_or(0, String::len)
+            + self.spans.len() * std::mem::size_of::<(usize, usize)>()
+            + self.group.as_ref().map_or(0, String::len)
+    }
+}
+
+/// Gathers matches for a search, enforcing `max_count` and `max_memory`.
+pub(crate) struct Collector {
+    results: SearchResults,
+    filter: Option<Where>,
+    max_count: usize,
+    budget: usize,
+    used: usize,
+}
+
+impl Collector {
+    pub(crate) fn new(options: &SearchOptions) -> Collector {
+        Collector {
+            results: SearchResults::default(),
+            filter: options.filter.clone(),
+            max_count: options.max_count.unwrap_or(usize::MAX),
+            budget: options.max_memory.unwrap_or(usize::MAX),
+            used: 0,
+        }
+    }
+
+    /// Whether `max_count` matching lines were seen, so scanning can stop
+    pub(crate) fn is_done(&self) -> bool {
+        self.results.matched_lines() >= self.max_count
+    }
+
+    /// Adds a matching line, returning whether it passed the filter and counted.
+    pub(crate) fn push(&mut self, m: LineMatch) -> bool {
+        if let Some(filter) = &self.filter
+            && !filter.keeps(&m.text)
+        {
+            return false;
+        }
+
+        let size = m.approx_size();
+        // Once over budget stay there, so kept results are a prefix of the input
+        if self.results.dropped_lines == 0 && self.used + size <= self.budget {
+            self.used += size;
+            self.results.matches.push(m);
+        } else {
+            self.results.dropped_lines += 1;
+        }
+        true
+    }
+
+    /// Counts a line that was cut to `max_line_length`
+    pub(crate) fn cut_line(&mut self) {
+        self.results.long_lines += 1;
+    }
+
+    pub(crate) fn finish(mut self, scanned_lines: usize) -> SearchResults {
+        self.results.scanned_lines = scanned_lines;
+        self.results
+    }
+}
+
+/// Searches a buffer that never touched the filesystem.
+///
+/// Invalid UTF-8 is replaced with U+FFFD before matching, so spans are byte
+/// offsets into each line's lossily decoded `text`.
+pub fn search_bytes(haystack: &[u8], matcher: &Matcher, options: &SearchOptions) -> SearchResults {
+    search_str(&String::from_utf8_lossy(haystack), matcher, options)
+}
+
+/// Drops a line's `\n` and the single `\r` before it, leaving the `\r` when
+/// `keep_cr` is set. Only one goes, so a `\r\r\n` line keeps its first.
+pub(crate) fn trim_line_end(line: &str, keep_cr: bool) -> &str {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    if keep_cr { line } else { line.strip_suffix('\r').unwrap_or(line) }
+}
+
+pub fn search_str(contents: &str, matcher: &Matcher, options: &SearchOptions) -> SearchResults {
+    let mut collector = Collector::new(options);
+    let mut scanned_lines = 0;
+
+    // Like `str::lines`, but leaving the `\r` in place when asked to
+    let lines = contents.split_inclusive('\n').map(|line| trim_line_end(line, options.keep_cr));
+
+    let line_limit = options.line_limit();
+
+    for (index, line) in lines.enumerate() {
+        if collector.is_done() {
+            break;
+        }
+        scanned_lines += 1;
+        let line = if line.len() > line_limit {
+            collector.cut_line();
+            let mut end = line_limit;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            &line[..end]
+        } else {
+            line
+        };
+        if let Some(m) = matcher.match_line(index, line) {
+            collector.push(m);
+        }
+    }
+
+    collector.finish(scanned_lines)
+}
+
+/// Words in `input` spelled like `query`, closest first and then most
+/// frequent, each with how many times it occurs. Used by `--suggest` when
+/// nothing matched, so it reads the whole input again, though only
+/// `line_limit` bytes of a line and the close words are kept at a time.
+pub fn suggest(mut input: impl BufRead, query: &str, limit: usize, line_limit: usize) -> io::Result<Vec<(String, usize)>> {
+    // Roughly one typo per three letters, but always allow one; swapped letters count as one
+    let query = query.to_lowercase();
+    let query_len = query.chars().count();
+    let max_distance = (query_len / 3).max(1);
+
+    let mut close: HashMap<String, (usize, usize)> = HashMap::new();
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if read_line_capped(&mut input, &mut buf, line_limit)?.0 == 0 {
+            break;
+        }
+        let text = String::from_utf8_lossy(&buf);
+        let text = match strip_ansi(&text) {
+            Some((plain, _)) => Cow::Owned(plain),
+            None => text,
+        };
+
+        for word in text.split(|c: char| !c.is_alphanumeric() && c != '_' && c != '\'') {
+            let word = word.trim_matches('\'');
+            // Words this much longer or shorter can't be close enough
+            if word.is_empty() || word == query || word.chars().count().abs_diff(query_len) > max_distance {
+                continue;
+            }
+            if let Some((_, hits)) = close.get_mut(word) {
+                *hits += 1;
+                continue;
+            }
+            let distance = damerau_levenshtein(&word.to_lowercase(), &query);
+            if distance <= max_distance {
+                close.insert(word.to_string(), (distance, 1));
+            }
+        }
+    }
+
+    let mut close: Vec<(usize, String, usize)> = close.into_iter().map(|(word, (distance, hits))| (distance, word, hits)).collect();
+    close.sort_by(|a, b| a.0.cmp(&b.0).then(b.2.cmp(&a.2)).then(a.1.cmp(&b.1)));
+    Ok(close.into_iter().take(limit).map(|(_, word, hits)| (word, hits)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn searches_invalid_utf8_lossily() {
+        let haystack = b"caf\xe9 au lait\nplain tea\n\xff\xfe tea again";
+        let matcher = Matcher::literal("tea", false);
+
+        let results = search_bytes(haystack, &matcher, &SearchOptions::default());
+
+        assert_eq!(results.scanned_lines, 3);
+        assert_eq!(results.matches.iter().map(|m| m.index).collect::<Vec<_>>(), vec![1, 2]);
+        let last = &results.matches[1];
+        assert_eq!(&last.text[last.spans[0].0..last.spans[0].1], "tea");
+        assert!(last.text.starts_with('\u{fffd}'));
+    }
+
+    #[test]
+    fn options_and_regex_matcher() {
+        let matcher = Matcher::regex(r"\d+", false).unwrap();
+        let options = SearchOptions { max_count: Some(1), ..Default::default() };
+
+        let results = search_bytes(b"a1\nb22\nc", &matcher, &options);
+
+        assert_eq!(results.matches.len(), 1);
+        assert_eq!(results.matches[0].spans, vec![(1, 2)]);
+    }
+
+    #[test]
+    fn memory_budget_drops_later_matches() {
+        let matcher = Matcher::literal("x", false);
+        let one_line = matcher.match_line(0, "x 0").unwrap().approx_size();
+        let options = SearchOptions { max_memory: Some(one_line * 2), ..Default::default() };
+
+        let results = search_bytes(b"x 0\nx 1\nx 2\nx 3", &matcher, &options);
+
+        assert_eq!(results.matches.len(), 2);
+        assert_eq!(results.dropped_lines, 2);
+        assert_eq!(results.matched_lines(), 4);
+    }
+
+    #[test]
+    fn suggests_close_words() {
+        let text = b"receive it, \x1b[1mreceive\x1b[0m all.\nrelieve? Receive!\nrecipe";
+
+        let suggestions = suggest(&text[..], "recieve", 3, usize::MAX).unwrap();
+        assert_eq!(
+            suggestions,
+            vec![("receive".to_string(), 2), ("Receive".to_string(), 1), ("relieve".to_string(), 1)]
+        );
+        assert!(suggest(&text[..], "zzz", 3, usize::MAX).unwrap().is_empty());
+        // Only the start of each line is looked at
+        assert_eq!(
+            suggest(&text[..], "recieve", 3, 8).unwrap(),
+            vec![("receive".to_string(), 1), ("relieve".to_string(), 1), ("recipe".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn pipeline_stages() {
+        let stage = |query, invert| Stage { matcher: Matcher::literal(query, false), invert };
+        let matcher = Matcher::Pipeline(vec![stage("error", false), stage("debug", true), stage("ror: re", false)]);
+
+        assert_eq!(matcher.spans("error: retry"), Some(vec![(0, 9)]));
+        assert_eq!(matcher.spans("error: retry (debug)"), None);
+        assert_eq!(matcher.spans("error: giving up"), None);
+
+        let inverted = Matcher::Pipeline(vec![stage("debug", true)]);
+        assert_eq!(inverted.spans("info: started"), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,109 @@
+use std::env;
+
+use colored::{ColoredString, Colorize};
+
+/// Terminal background brightness, which decides the secondary colors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Background {
+    #[default]
+    Dark,
+    Light,
+}
+
+/// Colors for everything that isn't a match: the line-number gutter and headings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Theme {
+    pub background: Background,
+}
+
+impl Theme {
+    /// Builds the theme for `--theme=auto|light|dark`.
+    pub fn from_name(name: &str) -> Result<Theme, String> {
+        let background = match name {
+            "auto" => detect_background(),
+            "light" => Background::Light,
+            "dark" => Background::Dark,
+            _ => return Err(format!("Unknown theme '{name}'. Expected 'auto', 'light' or 'dark'")),
+        };
+        Ok(Theme { background })
+    }
+
+    /// The line-number gutter, kept low-contrast but readable on either background
+    pub fn gutter(&self, s: &str) -> ColoredString {
+        match self.background {
+            Background::Dark => s.bright_black(),
+            Background::Light => s.black(),
+        }
+    }
+
+    /// Headings such as `--group-by` group names
+    pub fn heading(&self, s: &str) -> ColoredString {
+        match self.background {
+            Background::Dark => s.cyan().bold(),
+            Background::Light => s.blue().bold(),
+        }
+    }
+}
+
+/// How matches are drawn, from `--render=ansi|ansi-debug`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Render {
+    /// Terminal colors
+    #[default]
+    Ansi,
+    /// `<<match>>` markers and no escape codes at all, so output can be
+    /// compared against golden files whatever the terminal
+    AnsiDebug,
+}
+
+impl Render {
+    pub fn from_name(name: &str) -> Result<Render, String> {
+        match name {
+            "ansi" => Ok(Render::Ansi),
+            "ansi-debug" => Ok(Render::AnsiDebug),
+            _ => Err(format!("Unknown render mode '{name}'. Expected 'ansi' or 'ansi-debug'")),
+        }
+    }
+}
+
+/// Guesses the background from `COLORFGBG` (set by rxvt, Konsole and others as
+/// `fg;bg`). Falls back to dark, the most common terminal default.
+pub fn detect_background() -> Background {
+    env::var("COLORFGBG")
+        .ok()
+        .and_then(|value| parse_colorfgbg(&value))
+        .unwrap_or_default()
+}
+
+fn parse_colorfgbg(value: &str) -> Option<Background> {
+    // Some terminals insert a middle field, the background is always last
+    let bg: u8 = value.rsplit(';').next()?.parse().ok()?;
+    match bg {
+        7 | 9..=15 => Some(Background::Light),
+        0..=6 | 8 => Some(Background::Dark),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colorfgbg_background() {
+        assert_eq!(parse_colorfgbg("15;0"), Some(Background::Dark));
+        assert_eq!(parse_colorfgbg("0;15"), Some(Background::Light));
+        assert_eq!(parse_colorfgbg("0;default;7"), Some(Background::Light));
+        assert_eq!(parse_colorfgbg("default;default"), None);
+        assert_eq!(parse_colorfgbg(""), None);
+    }
+
+    #[test]
+    fn theme_names() {
+        assert_eq!(Theme::from_name("light").unwrap().background, Background::Light);
+        assert_eq!(Theme::from_name("dark").unwrap().background, Background::Dark);
+        assert!(Theme::from_name("solarized").is_err());
+        assert_eq!(Render::from_name("ansi-debug"), Ok(Render::AnsiDebug));
+        assert!(Render::from_name("html").is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use crate::pattern::Regex;
+
+/// A transformation applied to matched lines before they are shown (`--map=EXPR`).
+///
+/// - `field:N` keeps the N-th whitespace-separated field (1-based)
+/// - `field:N:SEP` splits on `SEP` instead of whitespace
+/// - `extract:REGEX` keeps the first capture group of the first match, or the
+///   whole match when the pattern has no groups
+#[derive(Debug, Clone)]
+pub enum LineMap {
+    Field { index: usize, separator: Option<String> },
+    Extract(Regex),
+}
+
+impl LineMap {
+    pub fn parse(expr: &str, ignore_case: bool) -> Result<LineMap, String> {
+        let usage = || {
+            format!("Invalid map expression '{expr}'. Use 'field:N', 'field:N:SEP' or 'extract:REGEX'")
+        };
+        let (kind, rest) = expr.split_once(':').ok_or_else(usage)?;
+
+        match kind {
+            "field" => {
+                let (index, separator) = match rest.split_once(':') {
+                    Some((index, separator)) if !separator.is_empty() => (index, Some(separator.to_string())),
+                    Some(_) => return Err(usage()),
+                    None => (rest, None),
+                };
+                match index.parse::<usize>() {
+                    Ok(index) if index > 0 => Ok(LineMap::Field { index, separator }),
+                    _ => Err(format!("Invalid field '{index}' in '--map', fields are numbered from 1")),
+                }
+            }
+            "extract" => Ok(LineMap::Extract(Regex::new(rest, ignore_case)?)),
+            _ => Err(usage()),
+        }
+    }
+
+    /// The part of `line` to keep, or `None` when the line doesn't have it.
+    pub fn apply<'a>(&self, line: &'a str) -> Option<&'a str> {
+        match self {
+            LineMap::Field { index, separator: None } => line.split_whitespace().nth(index - 1),
+            LineMap::Field { index, separator: Some(separator) } => line.split(separator.as_str()).nth(index - 1),
+            LineMap::Extract(regex) => {
+                let caps = regex.captures(line)?;
+                let (start, end) = if regex.group_count() > 0 { caps[1]? } else { caps[0]? };
+                Some(&line[start..end])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_selection() {
+        let map = LineMap::parse("field:3", false).unwrap();
+        assert_eq!(map.apply("GET  /index.html 200"), Some("200"));
+        assert_eq!(map.apply("GET /"), None);
+
+        let map = LineMap::parse("field:2:,", false).unwrap();
+        assert_eq!(map.apply("a,b,c"), Some("b"));
+    }
+
+    #[test]
+    fn regex_extraction() {
+        let map = LineMap::parse(r"extract:GET (\S+)", false).unwrap();
+        assert_eq!(map.apply("127.0.0.1 GET /api/users 200"), Some("/api/users"));
+
+        let map = LineMap::parse(r"extract:\d+ms", false).unwrap();
+        assert_eq!(map.apply("took 42ms"), Some("42ms"));
+        assert_eq!(map.apply("took long"), None);
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        assert!(LineMap::parse("field:0", false).is_err());
+        assert!(LineMap::parse("field:x", false).is_err());
+        assert!(LineMap::parse("upper", false).is_err());
+        assert!(LineMap::parse("extract:(", false).is_err());
+    }
+}